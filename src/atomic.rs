@@ -0,0 +1,89 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Replace the vault's files without leaving them half-written if we're
+//! interrupted, as other clients may be reading them at any time.
+
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::prelude::*;
+
+use super::{Result, Context};
+
+/// Write the data into a temporary file next to the destination and move it
+/// into place once it's safely on disk.
+pub fn write(p: &Path, data: &[u8]) -> Result<()> {
+    replace(p, data).in_file(p)
+}
+
+fn replace(p: &Path, data: &[u8]) -> Result<()> {
+    let name = match p.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Err(From::from(::std::io::Error::from(::std::io::ErrorKind::InvalidInput))),
+    };
+    let tmp = p.with_file_name(format!(".{}.tmp", name));
+
+    // A leftover from an earlier attempt would keep its own permissions
+    let _ = fs::remove_file(&tmp);
+    {
+        let mut f = create_private(&tmp)?;
+        // Keep the permissions of the file we're replacing
+        if let Ok(metadata) = fs::metadata(p) {
+            f.set_permissions(metadata.permissions())?;
+        }
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+
+    if let Err(e) = fs::rename(&tmp, p) {
+        let _ = fs::remove_file(&tmp);
+        return Err(From::from(e));
+    }
+
+    Ok(())
+}
+
+/// New files are only readable by their owner, as they hold the vault's data
+#[cfg(unix)]
+fn create_private(p: &Path) -> ::std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(p)
+}
+
+#[cfg(not(unix))]
+fn create_private(p: &Path) -> ::std::io::Result<fs::File> {
+    fs::File::create(p)
+}
+
+/// A path in the directory for a copy of the file which doesn't overwrite an
+/// earlier copy. The directory is created if needed.
+pub fn backup_path(dir: &Path, file: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir).in_file(dir)?;
+    let name = match file.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(From::from(::std::io::Error::from(::std::io::ErrorKind::InvalidInput))),
+    };
+
+    let mut dest = dir.join(&name);
+    let mut n = 1;
+    while dest.exists() {
+        dest = dir.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+
+    Ok(dest)
+}
+
+/// Move a file, copying it if it's going to a different filesystem
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to).in_file(to)?;
+        fs::remove_file(from).in_file(from)?;
+    }
+
+    Ok(())
+}
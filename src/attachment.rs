@@ -11,23 +11,30 @@ use std::io::SeekFrom;
 use std::io::prelude::*;
 use std::collections::HashMap;
 use std::slice::Iter as SliceIter;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use base64;
+use byteorder::{ByteOrder, LittleEndian};
 use serde_json;
-use super::{Result, Uuid, OverviewKey, ItemKey};
-use super::{opcldat, opdata01};
+use serde_json::{Map, Value};
+use super::{Result, Error, Context, Uuid, OverviewKey, ItemKey, Zeroizing};
+use super::{opcldat, opdata01, atomic, crypto};
+use super::item::{uuid_string, uuid_ser, timestamp};
+use super::integrity::BadAttachment;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct AttachmentData {
+    #[serde(serialize_with = "uuid_ser")]
     pub itemUUID: Uuid,
     pub contentsSize: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub external: Option<bool>,
     pub updatedAt: i64,
     pub txTimestamp: i64,
     pub overview: String,
     pub createdAt: i64,
+    #[serde(serialize_with = "uuid_ser")]
     pub uuid: Uuid,
 }
 
@@ -42,12 +49,12 @@ pub struct Attachment {
     pub created_at: i64,
     pub uuid: Uuid,
     path: PathBuf,
-    key: Rc<ItemKey>,
-    overview_key: Rc<OverviewKey>,
+    key: Arc<ItemKey>,
+    overview_key: Arc<OverviewKey>,
 }
 
 impl Attachment {
-    fn from_attachment_data(d: &AttachmentData, p: PathBuf, key: Rc<ItemKey>, overview_key: Rc<OverviewKey>) -> Result<Attachment> {
+    fn from_attachment_data(d: &AttachmentData, p: PathBuf, key: Arc<ItemKey>, overview_key: Arc<OverviewKey>) -> Result<Attachment> {
         let overview = base64::decode(&d.overview)?;
 
         Ok(Attachment {
@@ -66,43 +73,100 @@ impl Attachment {
     }
 
     /// Decrypt the attachment's overview data
-    pub fn decrypt_overview(&self) -> Result<Vec<u8>> {
+    pub fn decrypt_overview(&self) -> Result<Zeroizing<Vec<u8>>> {
         opdata01::decrypt(&self.overview[..], self.overview_key.encryption(), self.overview_key.verification())
     }
 
+    /// The attachment's file name, as stored in its overview
+    pub fn file_name(&self) -> Result<String> {
+        let overview: serde_json::Value = serde_json::from_slice(&self.decrypt_overview()?)?;
+        Ok(overview["filename"].as_str().unwrap_or("").to_string())
+    }
+
+    /// The attachment's file name made safe to use as a path: separators are
+    /// replaced, and a name which is empty, `.` or `..` becomes the UUID.
+    pub fn safe_file_name(&self) -> Result<String> {
+        let name: String = self.file_name()?.chars()
+            .map(|c| if c == '/' || c == '\\' || c == ':' || c.is_control() { '_' } else { c })
+            .collect();
+        match name.as_str() {
+            "" | "." | ".." => Ok(uuid_string(&self.uuid)),
+            _ => Ok(name),
+        }
+    }
+
     /// Decrypt the attachment's icon
-    pub fn decrypt_icon(&self) -> Result<Vec<u8>> {
+    pub fn decrypt_icon(&self) -> Result<Zeroizing<Vec<u8>>> {
+        self.read_icon().in_file(&self.path)
+    }
+
+    fn read_icon(&self) -> Result<Zeroizing<Vec<u8>>> {
         // The content is just after the metadata, so we need to open the file
         // again and figure out where things are.
-        let mut f = fs::File::open(&self.path)?;
-        let metadata = opcldat::read_header(&mut f)?;
+        let (mut f, metadata) = open_file(&self.path)?;
+        if metadata.icon_size == 0 {
+            return Ok(Zeroizing::new(Vec::new()));
+        }
 
-        let icon_offset = 16 /* header */ + metadata.metadata_size;
+        let icon_offset = opcldat::HEADER_SIZE + u64::from(metadata.metadata_size);
 
         let mut icon_data = vec![0u8; metadata.icon_size as usize];
-        f.seek(SeekFrom::Start(u64::from(icon_offset)))?;
+        f.seek(SeekFrom::Start(icon_offset))?;
         f.read_exact(&mut icon_data)?;
         opdata01::decrypt(&icon_data[..], self.key.encryption(), self.key.verification())
     }
 
+    /// Decrypt the attachment's content as it's read. This verifies the
+    /// content first and then decrypts a chunk at a time, so large attachments
+    /// need not be held in memory.
+    pub fn content_reader(&self) -> Result<opdata01::Reader<fs::File>> {
+        self.open_content().in_file(&self.path)
+    }
+
+    fn open_content(&self) -> Result<opdata01::Reader<fs::File>> {
+        let (f, metadata) = open_file(&self.path)?;
+
+        let content_offset = opcldat::HEADER_SIZE + u64::from(metadata.metadata_size) + u64::from(metadata.icon_size);
+        opdata01::Reader::new(f, content_offset, self.key.encryption(), self.key.verification())
+    }
+
+    /// Check that the overview, icon and content all verify, without keeping
+    /// the content in memory.
+    pub fn verify(&self) -> Result<()> {
+        self.decrypt_overview()?;
+        self.read_icon()?;
+        self.open_content()?;
+
+        Ok(())
+    }
+
     /// Decrypt the attachment's content
-    pub fn decrypt_content(&self) -> Result<Vec<u8>> {
+    pub fn decrypt_content(&self) -> Result<Zeroizing<Vec<u8>>> {
+        self.read_content().in_file(&self.path)
+    }
+
+    fn read_content(&self) -> Result<Zeroizing<Vec<u8>>> {
         // The content is just after the metadata, so we need to open the file
         // again and figure out where things are.
-        let mut f = fs::File::open(&self.path)?;
-        let metadata = opcldat::read_header(&mut f)?;
+        let (mut f, metadata) = open_file(&self.path)?;
 
-        let content_offset = 16 /* header */ + (metadata.metadata_size as usize) + (metadata.icon_size) as usize;
+        let content_offset = opcldat::HEADER_SIZE + u64::from(metadata.metadata_size) + u64::from(metadata.icon_size);
 
         let mut content_data = Vec::new();
-        f.seek(SeekFrom::Start(content_offset as u64))?;
+        f.seek(SeekFrom::Start(content_offset))?;
         f.read_to_end(&mut content_data)?;
         opdata01::decrypt(&content_data[..], self.key.encryption(), self.key.verification())
     }
 }
 
-pub fn read_attachments(p: &Path) -> Result<HashMap<Uuid, (AttachmentData, PathBuf)>> {
+/// The attachments' metadata and the files they're in, keyed by their UUID
+pub type AttachmentMap = HashMap<Uuid, (AttachmentData, PathBuf)>;
+
+/// Read the metadata of the attachments in the vault. Those we can't read are
+/// returned separately.
+pub fn read_attachments(p: &Path) -> Result<(AttachmentMap, Vec<BadAttachment>)> {
     let mut map = HashMap::new();
+    let mut bad = Vec::new();
     for entry in fs::read_dir(p)? {
         let entry = entry?;
         if entry.file_type()?.is_dir() {
@@ -111,19 +175,22 @@ pub fn read_attachments(p: &Path) -> Result<HashMap<Uuid, (AttachmentData, PathB
 
         if let Some(name) = entry.file_name().to_str() {
             if name.ends_with(".attachment") {
-                let (attachment, path) = read_attachment(&entry.path())?;
-                map.insert(attachment.uuid, (attachment, path));
+                let file = entry.path();
+                match read_attachment(&file) {
+                    Ok((attachment, path)) => {
+                        map.insert(attachment.uuid, (attachment, path));
+                    }
+                    Err(error) => bad.push(BadAttachment { file, uuid: None, error }),
+                }
             }
         }
     }
 
-    Ok(map)
+    Ok((map, bad))
 }
 
 pub fn read_attachment(p: &Path) -> Result<(AttachmentData, PathBuf)> {
-    let mut f = fs::File::open(p)?;
-
-    let metadata = opcldat::read_header(&mut f)?;
+    let (mut f, metadata) = open_file(p)?;
     let mut json_data = vec![0u8; metadata.metadata_size as usize];
     f.read_exact(&mut json_data)?;
     let data = serde_json::from_slice(&json_data)?;
@@ -131,11 +198,147 @@ pub fn read_attachment(p: &Path) -> Result<(AttachmentData, PathBuf)> {
     Ok((data, p.to_path_buf()))
 }
 
+/// Open an attachment file and read its header. The header's sizes are
+/// checked against the file's before anyone allocates buffers for them.
+fn open_file(p: &Path) -> Result<(fs::File, opcldat::Opcldat)> {
+    let mut f = fs::File::open(p)?;
+    let file_size = f.metadata()?.len();
+    let header = opcldat::read_header(&mut f)?;
+    if opcldat::HEADER_SIZE + u64::from(header.metadata_size) + u64::from(header.icon_size) > file_size {
+        return Err(Error::OpcldatError);
+    }
+
+    Ok((f, header))
+}
+
+/// Check that the sizes in an attachment's header are consistent with the
+/// file and with the metadata. Returns what's wrong with it, if anything.
+pub fn check_layout(p: &Path, data: &AttachmentData) -> Result<Option<&'static str>> {
+    let mut f = fs::File::open(p)?;
+    let file_size = f.metadata()?.len();
+    let header = opcldat::read_header(&mut f)?;
+    if header.version != opcldat::VERSION {
+        return Ok(Some("unknown format version"));
+    }
+
+    // Any icon is opdata01, which is at least 64 bytes and a whole number of
+    // blocks, and the content always is.
+    let icon_size = u64::from(header.icon_size);
+    if icon_size != 0 && (icon_size < 64 || !icon_size.is_multiple_of(16)) {
+        return Ok(Some("the icon size is not that of encrypted data"));
+    }
+    let content_offset = opcldat::HEADER_SIZE + u64::from(header.metadata_size) + icon_size;
+    if content_offset + 64 > file_size {
+        return Ok(Some("the sizes in the header are larger than the file"));
+    }
+    if !(file_size - content_offset).is_multiple_of(16) {
+        return Ok(Some("the content is not a whole number of blocks"));
+    }
+
+    f.seek(SeekFrom::Start(content_offset))?;
+    let mut content_header = [0u8; 16];
+    f.read_exact(&mut content_header)?;
+    if &content_header[..8] != b"opdata01" {
+        return Ok(Some("the content is not opdata01 data"));
+    }
+    if LittleEndian::read_u64(&content_header[8..]) != data.contentsSize {
+        return Ok(Some("the content size does not match the metadata"));
+    }
+
+    Ok(None)
+}
+
+/// Read the still-encrypted icon of an attachment
+fn read_raw_icon(p: &Path) -> Result<Vec<u8>> {
+    let (mut f, metadata) = open_file(p)?;
+
+    let mut icon_data = vec![0u8; metadata.icon_size as usize];
+    f.seek(SeekFrom::Current(i64::from(metadata.metadata_size)))?;
+    f.read_exact(&mut icon_data)?;
+
+    Ok(icon_data)
+}
+
+/// The attachments are stored next to the bands, named after the item they
+/// belong to and their own UUID.
+fn attachment_path(base: &Path, data: &AttachmentData) -> PathBuf {
+    base.join(format!("{}_{}.attachment", uuid_string(&data.itemUUID), uuid_string(&data.uuid)))
+}
+
+/// Write out an attachment file given its metadata and the already-encrypted
+/// icon and contents.
+fn write_attachment(path: &Path, data: &AttachmentData, icon: &[u8], content: &[u8]) -> Result<()> {
+    let metadata = serde_json::to_vec(data)?;
+    if metadata.len() > usize::from(u16::MAX) || icon.len() > u32::MAX as usize {
+        return Err(Error::OpcldatError);
+    }
+
+    let header = opcldat::Opcldat {
+        version: opcldat::VERSION,
+        metadata_size: metadata.len() as u16,
+        icon_size: icon.len() as u32,
+    };
+    let mut buf = Vec::with_capacity(opcldat::HEADER_SIZE as usize + metadata.len() + icon.len() + content.len());
+    opcldat::write_header(&mut buf, &header)?;
+    buf.extend_from_slice(&metadata);
+    buf.extend_from_slice(icon);
+    buf.extend_from_slice(content);
+
+    atomic::write(path, &buf)
+}
+
+/// Encrypt a new attachment for an item and write it into the vault. The
+/// file name goes into the overview, the icon and contents are encrypted with
+/// the item's key.
+pub fn new_attachment(base: &Path, item: &Uuid, name: &str, content: &[u8], icon: Option<&[u8]>, key: &ItemKey, overview_key: &OverviewKey) -> Result<(AttachmentData, PathBuf)> {
+    let mut overview = Map::new();
+    overview.insert("filename".to_string(), Value::String(name.to_string()));
+    let overview = opdata01::encrypt(&serde_json::to_vec(&overview)?, overview_key.encryption(), overview_key.verification())?;
+
+    let icon = match icon {
+        Some(icon) => opdata01::encrypt(icon, key.encryption(), key.verification())?,
+        None => Vec::new(),
+    };
+    let encrypted = opdata01::encrypt(content, key.encryption(), key.verification())?;
+
+    let now = timestamp();
+    let data = AttachmentData {
+        itemUUID: *item,
+        contentsSize: content.len() as u64,
+        external: None,
+        updatedAt: now,
+        txTimestamp: now,
+        overview: base64::encode(&overview),
+        createdAt: now,
+        uuid: crypto::random_uuid()?,
+    };
+    let path = attachment_path(base, &data);
+    write_attachment(&path, &data, &icon, &encrypted)?;
+
+    Ok((data, path))
+}
+
+/// Replace the contents of an attachment, keeping its name and icon. The file
+/// is rewritten where it is, whatever it's called.
+pub fn replace_content(data: &AttachmentData, p: &Path, content: &[u8], key: &ItemKey) -> Result<AttachmentData> {
+    let icon = read_raw_icon(p)?;
+    let encrypted = opdata01::encrypt(content, key.encryption(), key.verification())?;
+
+    let mut data = data.clone();
+    let now = timestamp();
+    data.contentsSize = content.len() as u64;
+    data.updatedAt = now;
+    data.txTimestamp = now;
+    write_attachment(p, &data, &icon, &encrypted)?;
+
+    Ok(data)
+}
+
 pub struct AttachmentIterator<'a> {
     pub inner: SliceIter<'a, Uuid>,
     pub atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>,
-    pub key: Rc<ItemKey>,
-    pub overview: Rc<OverviewKey>,
+    pub key: Arc<ItemKey>,
+    pub overview: Arc<OverviewKey>,
 }
 
 impl<'a> Iterator for AttachmentIterator<'a> {
@@ -148,6 +351,6 @@ impl<'a> Iterator for AttachmentIterator<'a> {
     }
 }
 
-pub fn from_data(attachment: &AttachmentData, p: PathBuf, key: Rc<ItemKey>, overview_key: Rc<OverviewKey>) -> Result<Attachment> {
+pub fn from_data(attachment: &AttachmentData, p: PathBuf, key: Arc<ItemKey>, overview_key: Arc<OverviewKey>) -> Result<Attachment> {
     Attachment::from_attachment_data(attachment, p, key, overview_key)
 }
@@ -0,0 +1,159 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Result, Error, LockedVault, UnlockedVault};
+
+struct State {
+    locked: Option<LockedVault>,
+    unlocked: Option<UnlockedVault>,
+    last_used: Instant,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    timeout: Duration,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while using the vault doesn't leave the state itself
+        // inconsistent, so we can keep going.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Lock a vault after it hasn't been used for a while.
+///
+/// A background thread locks the vault once it has been idle for longer
+/// than the timeout. Using the vault while it's locked fails with
+/// `Error::Locked` until it's unlocked again with the master password.
+///
+/// As with `UnlockedVault::lock`, `Attachment`s and cloned `Folder`s taken
+/// out of the vault keep their keys alive until they're dropped, so they
+/// shouldn't be held on to past the timeout.
+pub struct AutoLockVault {
+    shared: Arc<Shared>,
+}
+
+impl AutoLockVault {
+    /// Start tracking an unlocked vault, which will be locked after it's been
+    /// idle for `timeout`.
+    pub fn new(vault: UnlockedVault, timeout: Duration) -> AutoLockVault {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                locked: None,
+                unlocked: Some(vault),
+                last_used: Instant::now(),
+                closed: false,
+            }),
+            cond: Condvar::new(),
+            timeout,
+        });
+
+        let timer = shared.clone();
+        thread::spawn(move || watch(&timer));
+
+        AutoLockVault {
+            shared,
+        }
+    }
+
+    /// Whether the vault is currently locked
+    pub fn is_locked(&self) -> bool {
+        self.shared.state().unlocked.is_none()
+    }
+
+    /// Lock the vault now
+    pub fn lock(&self) {
+        let mut state = self.shared.state();
+        lock(&mut state);
+    }
+
+    /// Unlock the vault with the user's master password. This does nothing if
+    /// the vault is already unlocked.
+    pub fn unlock(&self, password: &[u8]) -> Result<()> {
+        let mut state = self.shared.state();
+        if state.unlocked.is_some() {
+            return Ok(());
+        }
+
+        // Unlocking consumes the locked vault, so we need to open it again if
+        // the password was wrong.
+        let locked = match state.locked.take() {
+            Some(locked) => locked,
+            None => return Err(Error::Locked),
+        };
+        let reopened = locked.clone();
+        match locked.unlock(password) {
+            Ok(unlocked) => {
+                state.unlocked = Some(unlocked);
+                state.last_used = Instant::now();
+                self.shared.cond.notify_all();
+                Ok(())
+            },
+            Err(e) => {
+                state.locked = Some(reopened);
+                Err(e)
+            },
+        }
+    }
+
+    /// Run a function with the unlocked vault. This counts as activity and
+    /// resets the idle timer.
+    pub fn with<F, T>(&self, f: F) -> Result<T>
+        where F: FnOnce(&mut UnlockedVault) -> T {
+        let mut state = self.shared.state();
+        state.last_used = Instant::now();
+        match state.unlocked {
+            Some(ref mut vault) => Ok(f(vault)),
+            None => Err(Error::Locked),
+        }
+    }
+}
+
+impl Drop for AutoLockVault {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.closed = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+fn lock(state: &mut State) {
+    if let Some(vault) = state.unlocked.take() {
+        state.locked = Some(vault.lock());
+    }
+}
+
+/// Wait until the vault has been idle for long enough and lock it
+fn watch(shared: &Shared) {
+    let mut state = shared.state();
+    loop {
+        if state.closed {
+            return;
+        }
+
+        if state.unlocked.is_none() {
+            state = shared.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+            continue;
+        }
+
+        let idle = state.last_used.elapsed();
+        if idle >= shared.timeout {
+            lock(&mut state);
+        } else {
+            state = shared.cond.wait_timeout(state, shared.timeout - idle)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
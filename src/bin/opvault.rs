@@ -0,0 +1,855 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Read and edit the items in a vault from the command line
+
+extern crate opvault;
+extern crate rpassword;
+extern crate serde_json;
+
+use std::env;
+use std::error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use opvault::{LockedVault, UnlockedVault, Item, Attachment, Category, CsvLayout, Detail, LoginFieldKind, Overview, URL, Uuid, Zeroizing};
+
+const USAGE: &str = "\
+usage: opvault [options] <command> [<args>]
+
+Options:
+    -v, --vault <path>      the vault to open, or $OPVAULT_PATH
+    --password-fd <fd>      read the master password from this file descriptor
+                            instead of $OPVAULT_PASSWORD or the terminal
+
+Commands:
+    list [--trashed]                 list the items
+    show [--reveal] <item>           show an item's details
+    get <item> <field>               print a single field of an item
+    attachments <item>               list an item's attachments
+    extract <attachment> [-o <file>] decrypt an attachment, into the directory
+                                     under its own name if <file> is one
+    folders                          list the folders
+    check                            look for damage in the vault
+
+    add <category> [<edit options>]  add an item and print its UUID
+    edit <item> [<edit options>]     change an item
+    rm [--purge] <item>              move an item to the trash, or delete it
+    mv <item> <folder>               move an item into a folder, or `-` for none
+    attach [--name <name>] <item> <file>
+                                     attach a file and print the attachment's UUID
+    generate [--length <n>] [--no-symbols]
+                                     print a random password
+    export [--format <format>] [--columns <columns>] [-o <file>]
+                                     export the vault as `1pif` (default), `1pux`,
+                                     `kdbx`, `bitwarden`, `bitwarden-encrypted`,
+                                     `csv`, `chrome`, `firefox` or `lastpass`
+    import [--format <format>] [--columns <columns>] <file>
+                                     add the items in a `1pif` (default), `csv`,
+                                     `chrome`, `firefox` or `lastpass` file and
+                                     print their UUIDs
+
+Edit options:
+    --title <title>, --username <name>, --url <url>, --notes <text>
+    --password <password>   `-` reads it from stdin
+    --generate              set a random password, see `generate` for options
+    --tag <tag>             add a tag, may be given several times
+    --folder <folder>       put the item in this folder
+    --json                  read {\"overview\": ..., \"detail\": ...} from stdin,
+                            either part replaces the item's own
+
+Items are given by their UUID or title. Fields are `title`, `url`,
+`username`, `password`, `notes`, or the name or title of any other field,
+optionally as `section.field`. Categories are `login`, `creditcard`, `note`,
+`identity`, `password`, `software`, `bank`, `database`, `driver`, `outdoor`,
+`membership`, `passport`, `rewards`, `ssn`, `router`, `server` and `email`.
+Files given as `-` are read from stdin. Exports which are encrypted take their
+password from $OPVAULT_EXPORT_PASSWORD or the terminal. CSV exports have the
+columns `title,username,url,folder,tags` unless --columns gives others, as a
+comma-separated list of fields, `folder`, `tags`, `favorite`, `totp` or
+`category`, each optionally headed as in `Server=server.url`. CSV imports take
+the columns from the file's headers unless --columns gives them.";
+
+/// What `add` accepts as a category
+const CATEGORIES: &[(&str, Category)] = &[
+    ("login", Category::Login),
+    ("creditcard", Category::CreditCard),
+    ("note", Category::SecureNote),
+    ("securenote", Category::SecureNote),
+    ("identity", Category::Identity),
+    ("password", Category::Password),
+    ("software", Category::SoftwareLicense),
+    ("bank", Category::BankAccount),
+    ("database", Category::Database),
+    ("driver", Category::DriverLicense),
+    ("outdoor", Category::OutdoorLicense),
+    ("membership", Category::Membership),
+    ("passport", Category::Passport),
+    ("rewards", Category::Rewards),
+    ("ssn", Category::SSN),
+    ("router", Category::Router),
+    ("server", Category::Server),
+    ("email", Category::Email),
+];
+
+const EDIT_FLAGS: &[&str] = &["--generate", "--no-symbols", "--json"];
+const EDIT_VALUES: &[&str] = &["--title", "--username", "--password", "--url", "--notes", "--tag", "--folder", "--length"];
+
+type Result<T> = ::std::result::Result<T, Box<dyn error::Error>>;
+
+/// Print a line to stdout, returning the error if e.g. the pipe was closed
+macro_rules! out {
+    ($($arg:tt)*) => { writeln!(io::stdout(), $($arg)*)? }
+}
+
+/// A mistake in the command line, which gets the usage printed
+#[derive(Debug)]
+struct UsageError(String);
+
+impl ::std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for UsageError {}
+
+fn usage<T>(msg: &str) -> Result<T> {
+    Err(Box::new(UsageError(msg.to_string())))
+}
+
+struct Options {
+    vault: Option<PathBuf>,
+    password_fd: Option<i32>,
+    command: String,
+    args: Vec<String>,
+}
+
+fn parse_options(mut args: env::Args) -> Result<Options> {
+    let mut opts = Options {
+        vault: env::var_os("OPVAULT_PATH").map(PathBuf::from),
+        password_fd: None,
+        command: String::new(),
+        args: Vec::new(),
+    };
+
+    // Skip the program's name
+    args.next();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" | "--vault" => match args.next() {
+                Some(path) => opts.vault = Some(PathBuf::from(path)),
+                None => return usage("--vault needs a path"),
+            },
+            "--password-fd" => match args.next().and_then(|fd| fd.parse().ok()) {
+                Some(fd) => opts.password_fd = Some(fd),
+                None => return usage("--password-fd needs a file descriptor"),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return usage(&format!("unknown option {}", arg)),
+            _ => {
+                opts.command = arg;
+                opts.args = args.collect();
+                return Ok(opts);
+            }
+        }
+    }
+
+    usage("no command given")
+}
+
+/// Take the flags out of the command's arguments. Those in `with_value` take
+/// the next argument as their value.
+fn take_flags(args: &mut Vec<String>, flags: &[&str], with_value: &[&str]) -> Result<Vec<(String, Option<String>)>> {
+    let mut taken = Vec::new();
+    let mut rest = Vec::new();
+    let mut iter = args.drain(..);
+    while let Some(arg) = iter.next() {
+        if flags.contains(&arg.as_str()) {
+            taken.push((arg, None));
+        } else if with_value.contains(&arg.as_str()) {
+            match iter.next() {
+                Some(value) => taken.push((arg, Some(value))),
+                None => return usage(&format!("{} needs a value", arg)),
+            }
+        } else if arg.starts_with('-') && arg != "-" {
+            return usage(&format!("unknown option {}", arg));
+        } else {
+            rest.push(arg);
+        }
+    }
+    drop(iter);
+    *args = rest;
+
+    Ok(taken)
+}
+
+/// Check the number of positional arguments
+fn expect_args(args: &[String], n: usize) -> Result<()> {
+    if args.len() != n {
+        return usage(&format!("expected {} argument{}", n, if n == 1 { "" } else { "s" }));
+    }
+
+    Ok(())
+}
+
+/// Read the password from the file descriptor, the environment or the
+/// terminal, in that order.
+fn read_password(fd: Option<i32>) -> Result<Zeroizing<String>> {
+    if let Some(fd) = fd {
+        // Room for any reasonable password, so the buffer isn't reallocated
+        // and a copy left behind
+        let mut password = Zeroizing::new(Vec::with_capacity(1024));
+        // A byte at a time, as the descriptor may be stdin and whatever comes
+        // after the line is for someone else
+        let mut file = open_fd(fd)?;
+        let mut byte = [0u8];
+        while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+            password.push(byte[0]);
+        }
+        if password.last() == Some(&b'\r') {
+            password.pop();
+        }
+        if std::str::from_utf8(&password).is_err() {
+            return Err(From::from("the password is not valid UTF-8"));
+        }
+        return Ok(Zeroizing::new(String::from_utf8(std::mem::take(&mut *password))?));
+    }
+
+    if let Some(password) = env::var_os("OPVAULT_PASSWORD") {
+        return match password.into_string() {
+            Ok(password) => Ok(Zeroizing::new(password)),
+            Err(_) => Err(From::from("OPVAULT_PASSWORD is not valid UTF-8")),
+        };
+    }
+
+    Ok(Zeroizing::new(rpassword::prompt_password("Master password: ")?))
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<File> {
+    use std::os::unix::io::BorrowedFd;
+
+    if fd < 0 {
+        return usage("--password-fd needs a file descriptor");
+    }
+    // We read through a duplicate so the descriptor stays open, as it may be
+    // e.g. stdin. Duplicating fails if it isn't open at all.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    match fd.try_clone_to_owned() {
+        Ok(owned) => Ok(File::from(owned)),
+        Err(e) => Err(From::from(format!("can't read from --password-fd: {}", e))),
+    }
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<File> {
+    Err(From::from("--password-fd is only supported on unix"))
+}
+
+/// Create a file for decrypted data which only the user can read
+#[cfg(unix)]
+fn create_private(path: &Path) -> Result<File> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().write(true).create(true).truncate(true).open(path)?)
+}
+
+/// Unlock the vault, salvaging what we can from damaged band files if asked
+fn unlock(opts: &Options, salvage: bool) -> Result<UnlockedVault> {
+    let path = match opts.vault {
+        Some(ref path) => path,
+        None => return usage("no vault given, use --vault or set OPVAULT_PATH"),
+    };
+    let vault = LockedVault::open(path)?;
+    let password = read_password(opts.password_fd)?;
+
+    if salvage {
+        Ok(vault.unlock_for_repair(password.as_bytes())?)
+    } else {
+        Ok(vault.unlock(password.as_bytes())?)
+    }
+}
+
+fn uuid_string(uuid: &Uuid) -> String {
+    format!("{:X}", uuid.to_simple_ref())
+}
+
+fn title(item: &Item) -> Result<String> {
+    Ok(item.overview()?.title.unwrap_or_default())
+}
+
+/// Find an item by its UUID or title
+fn find_item<'a>(vault: &'a UnlockedVault, query: &str) -> Result<Item<'a>> {
+    if let Ok(uuid) = Uuid::parse_str(query) {
+        if let Some(item) = vault.get_item(&uuid)? {
+            return Ok(item);
+        }
+    }
+
+    let mut found = Vec::new();
+    for item in vault.get_items().skip_trashed() {
+        if title(&item)?.eq_ignore_ascii_case(query) {
+            found.push(item);
+        }
+    }
+    match found.len() {
+        0 => Err(From::from(format!("no item matches '{}'", query))),
+        1 => Ok(found.remove(0)),
+        _ => Err(From::from(format!("several items are called '{}', use the UUID instead", query))),
+    }
+}
+
+/// Find an attachment by its UUID in any of the items
+fn find_attachment(vault: &UnlockedVault, query: &str) -> Result<Attachment> {
+    let uuid = Uuid::parse_str(query)?;
+    for item in vault.get_items() {
+        if item.attachments.contains(&uuid) {
+            if let Some(att) = item.get_attachment(&uuid) {
+                return Ok(att);
+            }
+        }
+    }
+
+    Err(From::from(format!("no attachment {}", query)))
+}
+
+fn folder_title(vault: &UnlockedVault, uuid: &Uuid) -> String {
+    match vault.folders.get(uuid).map(|f| f.overview()) {
+        Some(Ok(overview)) => overview.title,
+        _ => uuid_string(uuid),
+    }
+}
+
+fn list(vault: &UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &["--trashed"], &[])?;
+    expect_args(&args, 0)?;
+    let trashed = !flags.is_empty();
+
+    let mut items = Vec::new();
+    for item in vault.get_items() {
+        if item.is_trashed() && !trashed {
+            continue;
+        }
+        items.push((title(&item)?, uuid_string(&item.uuid), format!("{:?}", item.category)));
+    }
+    items.sort();
+    for (title, uuid, category) in items {
+        out!("{}\t{}\t{}", uuid, category, title);
+    }
+
+    Ok(())
+}
+
+fn show(vault: &UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &["--reveal"], &[])?;
+    expect_args(&args, 1)?;
+    let reveal = !flags.is_empty();
+    let secret = |value: &str| if reveal { value.to_string() } else { "********".to_string() };
+
+    let item = find_item(vault, &args[0])?;
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+
+    out!("Title: {}", overview.title.unwrap_or_default());
+    out!("UUID: {}", uuid_string(&item.uuid));
+    out!("Category: {:?}", item.category);
+    if let Some(ref folder) = item.folder {
+        out!("Folder: {}", folder_title(vault, folder));
+    }
+    if !overview.tags.is_empty() {
+        out!("Tags: {}", overview.tags.join(", "));
+    }
+    for url in &overview.urls {
+        out!("URL: {}", url.u);
+    }
+
+    match *detail {
+        Detail::Login(ref login) => {
+            for field in login.fields.iter().filter(|f| !f.value.is_empty() && f.kind != LoginFieldKind::Button) {
+                let name = field.designation.as_ref().unwrap_or(&field.name);
+                if name.is_empty() {
+                    continue;
+                }
+                let value = if field.kind == LoginFieldKind::Password { secret(&field.value) } else { field.value.clone() };
+                out!("{}: {}", name, value);
+            }
+        }
+        Detail::Password(ref password) => out!("password: {}", secret(&password.password)),
+        Detail::Generic(_) => (),
+    }
+    for section in detail.sections() {
+        if !section.title.is_empty() && !section.fields.is_empty() {
+            out!("\n[{}]", section.title);
+        }
+        for field in &section.fields {
+            let value = match field.value {
+                Some(ref value) => value.to_string(),
+                None => continue,
+            };
+            let value = if field.kind == opvault::FieldKind::Concealed { secret(&value) } else { value };
+            out!("{}: {}", field.title.as_deref().unwrap_or(&field.name), value);
+        }
+    }
+    if let Some(notes) = detail.notes() {
+        out!("\n{}", notes);
+    }
+
+    Ok(())
+}
+
+fn get(vault: &UnlockedVault, args: Vec<String>) -> Result<()> {
+    expect_args(&args, 2)?;
+    let item = find_item(vault, &args[0])?;
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+
+    let value = match args[1].as_str() {
+        "title" => overview.title,
+        "url" => overview.url.clone().or_else(|| overview.urls.first().map(|u| u.u.clone())),
+        "username" => detail.username().map(|s| s.to_string()),
+        "password" => detail.password().map(|s| s.to_string()),
+        "notes" => detail.notes().map(|s| s.to_string()),
+        _ => None,
+    };
+    // Other kinds of item keep e.g. the username in a custom field
+    let value = value.or_else(|| detail.field(&args[1]));
+    match value {
+        Some(value) => {
+            let value = Zeroizing::new(value);
+            out!("{}", *value);
+            Ok(())
+        }
+        None => Err(From::from(format!("the item has no field '{}'", args[1]))),
+    }
+}
+
+fn attachments(vault: &UnlockedVault, args: Vec<String>) -> Result<()> {
+    expect_args(&args, 1)?;
+    let item = find_item(vault, &args[0])?;
+    for att in item.get_attachments()? {
+        out!("{}\t{}\t{}", uuid_string(&att.uuid), att.contents_size, att.file_name()?);
+    }
+
+    Ok(())
+}
+
+fn extract(vault: &UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &[], &["-o", "--output"])?;
+    expect_args(&args, 1)?;
+    let att = find_attachment(vault, &args[0])?;
+
+    let mut reader = att.content_reader()?;
+    match flags.into_iter().next_back().and_then(|(_, path)| path) {
+        // A directory gets the file under the attachment's own name
+        Some(ref path) if Path::new(path).is_dir() => {
+            let path = Path::new(path).join(att.safe_file_name()?);
+            io::copy(&mut reader, &mut create_private(&path)?)?;
+        }
+        Some(ref path) if path != "-" => {
+            io::copy(&mut reader, &mut create_private(Path::new(path))?)?;
+        }
+        _ => {
+            let stdout = io::stdout();
+            io::copy(&mut reader, &mut stdout.lock())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn folders(vault: &UnlockedVault, args: Vec<String>) -> Result<()> {
+    expect_args(&args, 0)?;
+    let mut folders: Vec<(String, String, bool)> = vault.folders.iter()
+        .map(|(uuid, folder)| (folder_title(vault, uuid), uuid_string(uuid), folder.smart))
+        .collect();
+    folders.sort();
+    for (title, uuid, smart) in folders {
+        out!("{}\t{}{}", uuid, title, if smart { " (smart)" } else { "" });
+    }
+
+    Ok(())
+}
+
+fn check(vault: &UnlockedVault, args: Vec<String>) -> Result<()> {
+    expect_args(&args, 0)?;
+    let report = vault.check()?;
+    write!(io::stdout(), "{}", report)?;
+    if !report.is_clean() {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Read a file, or stdin if it's `-`
+fn read_input(path: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if path == "-" {
+        io::stdin().read_to_end(&mut buf)?;
+    } else {
+        File::open(path)?.read_to_end(&mut buf)?;
+    }
+
+    Ok(buf)
+}
+
+/// Find a folder by its UUID or title
+fn find_folder(vault: &UnlockedVault, query: &str) -> Result<Uuid> {
+    if let Ok(uuid) = Uuid::parse_str(query) {
+        if vault.folders.contains_key(&uuid) {
+            return Ok(uuid);
+        }
+    }
+
+    let found: Vec<Uuid> = vault.folders.iter()
+        .filter(|(_, folder)| !folder.smart)
+        .filter(|(uuid, _)| folder_title(vault, uuid).eq_ignore_ascii_case(query))
+        .map(|(uuid, _)| *uuid)
+        .collect();
+    match found.len() {
+        0 => Err(From::from(format!("no folder matches '{}'", query))),
+        1 => Ok(found[0]),
+        _ => Err(From::from(format!("several folders are called '{}', use the UUID instead", query))),
+    }
+}
+
+fn generate_options(flags: &[(String, Option<String>)]) -> Result<(usize, bool)> {
+    let mut length = 24;
+    let mut symbols = true;
+    for (flag, value) in flags {
+        match (flag.as_str(), value) {
+            ("--length", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => length = n,
+                _ => return usage("--length needs a positive number"),
+            },
+            ("--no-symbols", _) => symbols = false,
+            _ => (),
+        }
+    }
+
+    Ok((length, symbols))
+}
+
+/// Apply the edit options to an item's overview and details. Returns the
+/// folder the item should go into, if one was given.
+fn apply_edits(vault: &UnlockedVault, category: Category, overview: &mut Overview, detail: &mut Detail, flags: &[(String, Option<String>)]) -> Result<Option<Uuid>> {
+    if flags.iter().any(|(f, _)| f == "--json") {
+        if flags.iter().any(|(f, v)| f == "--password" && v.as_deref() == Some("-")) {
+            return usage("--json and --password - both read stdin");
+        }
+        let input = Zeroizing::new(read_input("-")?);
+        let json: serde_json::Value = serde_json::from_slice(&input)?;
+        if let Some(o) = json.get("overview") {
+            *overview = serde_json::from_value(o.clone())?;
+        }
+        if let Some(d) = json.get("detail") {
+            *detail = Detail::from_slice(category, &Zeroizing::new(serde_json::to_vec(d)?))?;
+        }
+    }
+
+    let mut password = None;
+    if flags.iter().any(|(f, _)| f == "--generate") {
+        let (length, symbols) = generate_options(flags)?;
+        password = Some(opvault::generate_password(length, symbols)?);
+    }
+
+    let mut folder = None;
+    for (flag, value) in flags {
+        let value = match *value {
+            Some(ref value) => value.clone(),
+            None => continue,
+        };
+        match flag.as_str() {
+            "--title" => overview.title = Some(value),
+            "--url" => {
+                match overview.urls.first_mut() {
+                    Some(url) => url.u = value.clone(),
+                    None => overview.urls.push(URL { u: value.clone() }),
+                }
+                overview.url = Some(value);
+            }
+            "--tag" if !overview.tags.contains(&value) => overview.tags.push(value),
+            "--notes" => detail.set_notes(Some(value)),
+            "--folder" => folder = Some(find_folder(vault, &value)?),
+            "--username" => match *detail {
+                Detail::Login(ref mut login) => {
+                    login.set_designated("username", value.clone());
+                    overview.ainfo = Some(value);
+                }
+                _ => return Err(From::from("only logins have a username")),
+            },
+            "--password" if value == "-" => {
+                let mut line = Zeroizing::new(String::new());
+                io::stdin().read_line(&mut line)?;
+                let len = line.trim_end_matches(['\r', '\n']).len();
+                line.truncate(len);
+                password = Some(line);
+            }
+            "--password" => password = Some(Zeroizing::new(value)),
+            _ => (),
+        }
+    }
+
+    if let Some(password) = password {
+        match *detail {
+            Detail::Login(ref mut login) => login.set_designated("password", password.to_string()),
+            Detail::Password(ref mut p) => p.password = password.to_string(),
+            Detail::Generic(_) => return Err(From::from("only logins and passwords have a password")),
+        }
+    }
+
+    Ok(folder)
+}
+
+fn add(vault: &mut UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, EDIT_FLAGS, EDIT_VALUES)?;
+    expect_args(&args, 1)?;
+    let category = match CATEGORIES.iter().find(|(name, _)| args[0].eq_ignore_ascii_case(name)) {
+        Some(&(_, category)) => category,
+        None => return usage(&format!("unknown category {}", args[0])),
+    };
+
+    let mut overview = Overview::default();
+    let mut detail = Zeroizing::new(Detail::new(category));
+    let folder = apply_edits(vault, category, &mut overview, &mut detail, &flags)?;
+    let uuid = vault.add_item(category, &overview, &detail)?;
+    if folder.is_some() {
+        vault.move_to_folder(&uuid, folder.as_ref())?;
+    }
+    out!("{}", uuid_string(&uuid));
+
+    Ok(())
+}
+
+fn edit(vault: &mut UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, EDIT_FLAGS, EDIT_VALUES)?;
+    expect_args(&args, 1)?;
+    let (uuid, category, mut overview, mut detail) = {
+        let item = find_item(vault, &args[0])?;
+        (item.uuid, item.category, item.overview()?, item.detail()?)
+    };
+
+    let folder = apply_edits(vault, category, &mut overview, &mut detail, &flags)?;
+    vault.update_item(&uuid, &overview, &detail)?;
+    if folder.is_some() {
+        vault.move_to_folder(&uuid, folder.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn rm(vault: &mut UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &["--purge"], &[])?;
+    expect_args(&args, 1)?;
+    let uuid = find_item(vault, &args[0])?.uuid;
+    if flags.is_empty() {
+        vault.trash_item(&uuid)?;
+    } else {
+        vault.purge_item(&uuid)?;
+    }
+
+    Ok(())
+}
+
+fn mv(vault: &mut UnlockedVault, args: Vec<String>) -> Result<()> {
+    expect_args(&args, 2)?;
+    let uuid = find_item(vault, &args[0])?.uuid;
+    let folder = match args[1].as_str() {
+        "-" => None,
+        query => Some(find_folder(vault, query)?),
+    };
+
+    Ok(vault.move_to_folder(&uuid, folder.as_ref())?)
+}
+
+fn attach(vault: &mut UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &[], &["--name"])?;
+    expect_args(&args, 2)?;
+    let uuid = find_item(vault, &args[0])?.uuid;
+    let name = match flags.into_iter().next_back().and_then(|(_, name)| name) {
+        Some(name) => name,
+        None if args[1] == "-" => return usage("attaching stdin needs a --name"),
+        None => match PathBuf::from(&args[1]).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return usage(&format!("{} has no file name, use --name", args[1])),
+        },
+    };
+
+    let content = Zeroizing::new(read_input(&args[1])?);
+    let att = vault.add_attachment(&uuid, &name, &content, None)?;
+    out!("{}", uuid_string(&att));
+
+    Ok(())
+}
+
+/// The password to encrypt an export with
+#[cfg(feature = "export")]
+fn export_password() -> Result<Zeroizing<String>> {
+    if let Some(password) = env::var_os("OPVAULT_EXPORT_PASSWORD") {
+        return match password.into_string() {
+            Ok(password) => Ok(Zeroizing::new(password)),
+            Err(_) => Err(From::from("OPVAULT_EXPORT_PASSWORD is not valid UTF-8")),
+        };
+    }
+
+    let password = Zeroizing::new(rpassword::prompt_password("Export password: ")?);
+    let again = Zeroizing::new(rpassword::prompt_password("Repeat export password: ")?);
+    if *password != *again {
+        return Err(From::from("the passwords don't match"));
+    }
+
+    Ok(password)
+}
+
+fn export(vault: &UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &[], &["--format", "--columns", "-o", "--output"])?;
+    expect_args(&args, 0)?;
+    let mut format = "1pif".to_string();
+    let mut columns = "title,username,url,folder,tags".to_string();
+    let mut output = None;
+    for (flag, value) in flags {
+        match flag.as_str() {
+            "--format" => format = value.unwrap_or_default(),
+            "--columns" => columns = value.unwrap_or_default(),
+            _ => output = value.filter(|path| path != "-"),
+        }
+    }
+
+    let mut buf = Vec::new();
+    match format.as_str() {
+        "1pif" => opvault::write_1pif(vault, &mut buf)?,
+        // The zip file is written out of order, so it's put together in
+        // memory first.
+        #[cfg(feature = "1pux")]
+        "1pux" => opvault::write_1pux(vault, io::Cursor::new(&mut buf))?,
+        #[cfg(feature = "export")]
+        "kdbx" => opvault::write_kdbx(vault, export_password()?.as_bytes(), &mut buf)?,
+        #[cfg(feature = "export")]
+        "bitwarden" => opvault::write_bitwarden(vault, None, &mut buf)?,
+        #[cfg(feature = "export")]
+        "bitwarden-encrypted" => opvault::write_bitwarden(vault, Some(export_password()?.as_bytes()), &mut buf)?,
+        "csv" => opvault::write_csv(vault, &CsvLayout::parse(&columns)?, &mut buf)?,
+        "chrome" => opvault::write_csv(vault, &CsvLayout::chrome(), &mut buf)?,
+        "firefox" => opvault::write_csv(vault, &CsvLayout::firefox(), &mut buf)?,
+        "lastpass" => opvault::write_csv(vault, &CsvLayout::lastpass(), &mut buf)?,
+        _ => return usage(&format!("unknown export format {}", format)),
+    }
+    let buf = Zeroizing::new(buf);
+    match output {
+        Some(path) => create_private(Path::new(&path))?.write_all(&buf)?,
+        None => io::stdout().write_all(&buf)?,
+    }
+
+    Ok(())
+}
+
+fn import(vault: &mut UnlockedVault, mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &[], &["--format", "--columns"])?;
+    expect_args(&args, 1)?;
+    let mut format = "1pif".to_string();
+    let mut columns = None;
+    for (flag, value) in flags {
+        match flag.as_str() {
+            "--format" => format = value.unwrap_or_default(),
+            _ => columns = value,
+        }
+    }
+
+    let input = Zeroizing::new(String::from_utf8(read_input(&args[0])?)?);
+    let added = match format.as_str() {
+        "1pif" => opvault::import_1pif(vault, &input)?,
+        "csv" => {
+            let layout = columns.map(|columns| CsvLayout::parse(&columns)).transpose()?;
+            opvault::import_csv(vault, layout.as_ref(), &input)?
+        }
+        "chrome" => opvault::import_csv(vault, Some(&CsvLayout::chrome()), &input)?,
+        "firefox" => opvault::import_csv(vault, Some(&CsvLayout::firefox()), &input)?,
+        "lastpass" => opvault::import_csv(vault, Some(&CsvLayout::lastpass()), &input)?,
+        _ => return usage(&format!("unknown import format {}", format)),
+    };
+    for uuid in added {
+        out!("{}", uuid_string(&uuid));
+    }
+
+    Ok(())
+}
+
+fn generate(mut args: Vec<String>) -> Result<()> {
+    let flags = take_flags(&mut args, &["--no-symbols"], &["--length"])?;
+    expect_args(&args, 0)?;
+    let (length, symbols) = generate_options(&flags)?;
+    out!("{}", *opvault::generate_password(length, symbols)?);
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let opts = parse_options(env::args())?;
+    let args = opts.args.clone();
+    // This one doesn't need the vault
+    if opts.command == "generate" {
+        return generate(args);
+    }
+    // Checking is how damage gets reported, so it mustn't fail on it
+    let mut vault = unlock(&opts, opts.command == "check")?;
+
+    match opts.command.as_str() {
+        "list" => list(&vault, args),
+        "show" => show(&vault, args),
+        "get" => get(&vault, args),
+        "attachments" => attachments(&vault, args),
+        "extract" => extract(&vault, args),
+        "folders" => folders(&vault, args),
+        "check" => check(&vault, args),
+        "add" => add(&mut vault, args),
+        "edit" => edit(&mut vault, args),
+        "rm" => rm(&mut vault, args),
+        "mv" => mv(&mut vault, args),
+        "attach" => attach(&mut vault, args),
+        "export" => export(&vault, args),
+        "import" => import(&mut vault, args),
+        cmd => usage(&format!("unknown command {}", cmd)),
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        // Whoever's reading our output has seen enough
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return;
+            }
+        }
+        eprintln!("opvault: {}", e);
+        if e.is::<UsageError>() {
+            eprintln!("\n{}", USAGE);
+            process::exit(2);
+        }
+        // Let scripts tell a typo in the password from a damaged vault
+        if let Some(&opvault::Error::WrongPassword) = e.downcast_ref() {
+            process::exit(3);
+        }
+        process::exit(1);
+    }
+}
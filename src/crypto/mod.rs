@@ -7,6 +7,224 @@
 
 //! This module wraps the different crypto implementations so we e.g. use
 //! CommonCrypto on macOS instead of OpenSSL.
+//!
+//! Each implementation provides the primitives in `Backend` and the rest of
+//! the crate uses the functions here which are built on top of them. The
+//! implementation is picked with cargo features: `openssl` (the default) or
+//! `rustcrypto` for one which doesn't need any system libraries. If both are
+//! enabled, OpenSSL is used.
 
-mod openssl;
-pub use self::openssl::{verify_data, decrypt_data, hmac, hash_sha512, pbkdf2, Error};
+use std::fmt;
+use std::error;
+use uuid::{Builder, Variant, Version};
+use super::{Result, Key, HmacKey, Uuid};
+#[cfg(feature = "export")]
+use super::Zeroizing;
+
+#[cfg(feature = "openssl")]
+pub mod openssl;
+#[cfg(feature = "rustcrypto")]
+#[cfg_attr(feature = "openssl", allow(dead_code))]
+pub mod rust;
+
+#[cfg(feature = "openssl")]
+use self::openssl::OpenSsl as Active;
+#[cfg(all(feature = "rustcrypto", not(feature = "openssl")))]
+use self::rust::RustCrypto as Active;
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("one of the `openssl` or `rustcrypto` features must be enabled");
+
+/// The errors the crypto implementations can produce
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "openssl")]
+    OpenSsl(::openssl::error::ErrorStack),
+    /// A key or IV of the wrong size, or data which is not made up of whole
+    /// blocks.
+    InvalidLength,
+    /// The system's random number generator failed
+    Random,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "openssl")]
+            Error::OpenSsl(ref e) => write!(f, "OpenSSL error: {}", e),
+            Error::InvalidLength => write!(f, "invalid key or data length"),
+            Error::Random => write!(f, "could not generate random data"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            #[cfg(feature = "openssl")]
+            Error::OpenSsl(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Feeds data into an HMAC calculation
+pub trait Signer {
+    fn update(&mut self, data: &[u8]) -> Result<()>;
+}
+
+/// Decrypts AES-256-CBC data a few blocks at a time
+pub trait Decrypt: Sized {
+    fn new(decrypt_key: &[u8], iv: &[u8]) -> Result<Self>;
+
+    /// Decrypt whole blocks of data. The output must be at least one block
+    /// larger than the input.
+    fn update(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize>;
+}
+
+/// The primitives a crypto implementation needs to provide
+pub trait Backend {
+    type Decrypter: Decrypt;
+
+    /// Derive a key with PBKDF2-HMAC-SHA512, filling the output
+    fn pbkdf2_sha512(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()>;
+
+    fn sha512(data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Calculate the HMAC-SHA256 of the data the callback feeds into it
+    fn hmac_sha256(key: &[u8], cb: &mut dyn FnMut(&mut dyn Signer) -> Result<()>) -> Result<Vec<u8>>;
+
+    /// AES-256-CBC without padding, the data must be a whole number of blocks
+    fn aes256_cbc_encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>;
+
+    /// AES-256-CBC without padding, the data must be a whole number of blocks
+    fn aes256_cbc_decrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>;
+
+    fn random_bytes(buf: &mut [u8]) -> Result<()>;
+
+    /// Compare two equally-sized buffers in constant time
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool;
+}
+
+/// The primitives only the exports for other password managers need
+#[cfg(feature = "export")]
+pub trait ExportBackend {
+    /// Derive a key with PBKDF2-HMAC-SHA256, filling the output
+    fn pbkdf2_sha256(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()>;
+
+    fn sha256(data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Encrypt whole blocks in place with AES-256-ECB, `rounds` times over.
+    /// This is the key transformation of KeePass' AES-KDF.
+    fn aes256_ecb_encrypt_rounds(data: &mut [u8], key: &[u8], rounds: u64) -> Result<()>;
+
+    /// XOR the data with the ChaCha20 keystream, starting at the given block
+    fn chacha20_xor(data: &mut [u8], key: &[u8], nonce: &[u8], block: u32) -> Result<()>;
+}
+
+pub type Decrypter = <Active as Backend>::Decrypter;
+
+/// The iteration counts the backends can all run PBKDF2 with. OpenSSL panics
+/// on anything which doesn't fit an `i32`, and the counts may come from an
+/// untrusted profile.
+fn check_iterations(iterations: usize) -> Result<()> {
+    if iterations == 0 || iterations > i32::MAX as usize {
+        return Err(super::Error::Crypto(Error::InvalidLength));
+    }
+
+    Ok(())
+}
+
+pub fn pbkdf2(pw: &[u8], salt: &[u8], iterations: usize) -> Result<Key> {
+    check_iterations(iterations)?;
+    // Wrap the buffer before writing into it so it's wiped on error as well
+    let mut derived = Key::from(vec![0u8; 64]);
+    Active::pbkdf2_sha512(pw, salt, iterations, derived.as_bytes_mut())?;
+
+    Ok(derived)
+}
+
+/// Derive a 32-byte key with PBKDF2-HMAC-SHA256, as other password managers
+/// use for their exports
+#[cfg(feature = "export")]
+pub fn pbkdf2_sha256(pw: &[u8], salt: &[u8], iterations: usize) -> Result<Zeroizing<Vec<u8>>> {
+    check_iterations(iterations)?;
+    let mut derived = Zeroizing::new(vec![0u8; 32]);
+    Active::pbkdf2_sha256(pw, salt, iterations, &mut derived)?;
+
+    Ok(derived)
+}
+
+pub fn hash_sha512(data: &[u8]) -> Result<Vec<u8>> {
+    Active::sha512(data)
+}
+
+#[cfg(feature = "export")]
+pub fn hash_sha256(data: &[u8]) -> Result<Vec<u8>> {
+    Active::sha256(data)
+}
+
+#[cfg(feature = "export")]
+pub fn aes_kdf(data: &mut [u8], key: &[u8], rounds: u64) -> Result<()> {
+    Active::aes256_ecb_encrypt_rounds(data, key, rounds)
+}
+
+#[cfg(feature = "export")]
+pub fn chacha20_xor(data: &mut [u8], key: &[u8], nonce: &[u8], block: u32) -> Result<()> {
+    Active::chacha20_xor(data, key, nonce, block)
+}
+
+pub fn decrypt_data(data: &[u8], decrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    Active::aes256_cbc_decrypt(data, decrypt_key, iv)
+}
+
+pub fn encrypt_data(data: &[u8], encrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    Active::aes256_cbc_encrypt(data, encrypt_key, iv)
+}
+
+pub fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    Active::random_bytes(buf)
+}
+
+/// Check the HMAC-SHA256 at the end of the data against the rest of it
+pub fn verify_data(data: &[u8], hmac_key: &[u8]) -> Result<bool> {
+    if data.len() < 32 {
+        return Err(super::Error::Crypto(Error::InvalidLength));
+    }
+
+    let (contents, mac) = data.split_at(data.len() - 32);
+    let computed_hmac = hmac(hmac_key, |signer| signer.update(contents))?;
+
+    Ok(verify_mac(&computed_hmac, mac))
+}
+
+/// Compare a MAC we calculated with the one we were given without leaking
+/// through the timing how much of it matched.
+pub fn verify_mac(computed: &[u8], given: &[u8]) -> bool {
+    // The length of a MAC is no secret
+    computed.len() == given.len() && Active::constant_time_eq(computed, given)
+}
+
+pub fn hmac<F>(key: &HmacKey, cb: F) -> Result<Vec<u8>>
+    where F: FnOnce(&mut dyn Signer) -> Result<()> {
+    // The backends take a trait object, so we need to be able to call this
+    // through a `FnMut`.
+    let mut cb = Some(cb);
+    Active::hmac_sha256(key, &mut |signer| {
+        match cb.take() {
+            Some(cb) => cb(signer),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Generate a random (version 4) UUID for a new item, folder or attachment.
+pub fn random_uuid() -> Result<Uuid> {
+    let mut bytes = [0u8; 16];
+    random_bytes(&mut bytes)?;
+
+    Ok(Builder::from_bytes(bytes)
+       .set_variant(Variant::RFC4122)
+       .set_version(Version::Random)
+       .build())
+}
@@ -7,85 +7,166 @@
 
 //! The OpenSSL implementation of our crypto functions
 
-use super::super::{Result, HmacKey};
+use super::super::Result;
+#[cfg(feature = "export")]
+use super::super::Zeroizing;
+use super::{Backend, Decrypt, Signer};
+#[cfg(feature = "export")]
+use super::ExportBackend;
 use openssl::symm;
 use openssl::sign;
 use openssl::pkey::PKey;
 use openssl::hash;
 use openssl::hash::MessageDigest;
 use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::memcmp;
 use openssl::error::ErrorStack;
 
-pub type Error = ErrorStack;
-
 impl From<ErrorStack> for super::super::Error {
     fn from(e: ErrorStack) -> Self {
-        super::super::Error::Crypto(e)
+        super::super::Error::Crypto(super::Error::OpenSsl(e))
     }
 }
 
+pub struct OpenSsl;
 
-pub fn pbkdf2(pw: &[u8], salt: &[u8], iterations: usize) -> Result<[u8; 64]> {
-    let mut derived = [0u8; 64];
-    pbkdf2_hmac(pw, salt, iterations, MessageDigest::sha512(), &mut derived)?;
+impl Backend for OpenSsl {
+    type Decrypter = Decrypter;
 
-    Ok(derived)
-}
+    fn pbkdf2_sha512(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()> {
+        pbkdf2_hmac(pw, salt, iterations, MessageDigest::sha512(), out)?;
+
+        Ok(())
+    }
 
-pub fn hash_sha512(data: &[u8]) -> Result<Vec<u8>> {
-    match hash::hash(MessageDigest::sha512(), data) {
-        Ok(x) => Ok(x.to_vec()),
-        Err(e) => Err(From::from(e)),
+    fn sha512(data: &[u8]) -> Result<Vec<u8>> {
+        match hash::hash(MessageDigest::sha512(), data) {
+            Ok(x) => Ok(x.to_vec()),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    fn hmac_sha256(key: &[u8], cb: &mut dyn FnMut(&mut dyn Signer) -> Result<()>) -> Result<Vec<u8>> {
+        let pkey = PKey::hmac(key)?;
+        let mut signer = sign::Signer::new(MessageDigest::sha256(), &pkey)?;
+        cb(&mut signer)?;
+
+        match signer.sign_to_vec() {
+            Ok(x) => Ok(x),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    fn aes256_cbc_encrypt(data: &[u8], encrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        crypt(symm::Mode::Encrypt, data, encrypt_key, iv)
+    }
+
+    fn aes256_cbc_decrypt(data: &[u8], decrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        crypt(symm::Mode::Decrypt, data, decrypt_key, iv)
+    }
+
+    fn random_bytes(buf: &mut [u8]) -> Result<()> {
+        rand_bytes(buf)?;
+
+        Ok(())
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        memcmp::eq(a, b)
     }
 }
 
+#[cfg(feature = "export")]
+impl ExportBackend for OpenSsl {
+    fn pbkdf2_sha256(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()> {
+        pbkdf2_hmac(pw, salt, iterations, MessageDigest::sha256(), out)?;
 
-pub fn decrypt_data(data: &[u8], decrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
-    let t = symm::Cipher::aes_256_cbc();
-    let mut crypter = symm::Crypter::new(t, symm::Mode::Decrypt, decrypt_key, Some(iv))?;
-    crypter.pad(false);
-    let mut decrypted = vec![0u8; data.len()+ t.block_size()];
-    let count = crypter.update(data, &mut decrypted[..])?;
-    let rest = crypter.finalize(&mut decrypted[count..])?;
+        Ok(())
+    }
+
+    fn sha256(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(hash::hash(MessageDigest::sha256(), data)?.to_vec())
+    }
+
+    fn aes256_ecb_encrypt_rounds(data: &mut [u8], key: &[u8], rounds: u64) -> Result<()> {
+        let t = symm::Cipher::aes_256_ecb();
+        if !data.len().is_multiple_of(t.block_size()) {
+            return Err(super::super::Error::Crypto(super::Error::InvalidLength));
+        }
+        let mut crypter = symm::Crypter::new(t, symm::Mode::Encrypt, key, None)?;
+        crypter.pad(false);
+
+        // ECB keeps no state between blocks, so we can keep feeding the
+        // output back in.
+        let mut out = Zeroizing::new(vec![0u8; data.len() + t.block_size()]);
+        for _ in 0..rounds {
+            let count = crypter.update(data, &mut out[..])?;
+            data.copy_from_slice(&out[..count]);
+        }
 
-    decrypted.truncate(count + rest);
-    Ok(decrypted)
+        Ok(())
+    }
+
+    fn chacha20_xor(data: &mut [u8], key: &[u8], nonce: &[u8], block: u32) -> Result<()> {
+        if nonce.len() != 12 {
+            return Err(super::super::Error::Crypto(super::Error::InvalidLength));
+        }
+        // OpenSSL takes the block counter as the first word of the IV
+        let mut iv = [0u8; 16];
+        iv[..4].copy_from_slice(&block.to_le_bytes());
+        iv[4..].copy_from_slice(nonce);
+
+        let t = symm::Cipher::chacha20();
+        let mut crypter = symm::Crypter::new(t, symm::Mode::Encrypt, key, Some(&iv))?;
+        let mut out = Zeroizing::new(vec![0u8; data.len() + t.block_size()]);
+        let count = crypter.update(data, &mut out[..])?;
+        data.copy_from_slice(&out[..count]);
+
+        Ok(())
+    }
 }
 
-pub fn verify_data(data: &[u8], hmac_key: &[u8]) -> Result<bool> {
-    let mac = &data[data.len() - 32..];
-    let pkey = PKey::hmac(hmac_key)?;
-    let mut signer = sign::Signer::new(MessageDigest::sha256(), &pkey)?;
-    signer.update(&data[..data.len() - 32])?;
-    let computed_hmac = signer.sign_to_vec()?;
+fn crypt(mode: symm::Mode, data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let t = symm::Cipher::aes_256_cbc();
+    let mut crypter = symm::Crypter::new(t, mode, key, Some(iv))?;
+    crypter.pad(false);
+    let mut out = vec![0u8; data.len() + t.block_size()];
+    let count = crypter.update(data, &mut out[..])?;
+    let rest = crypter.finalize(&mut out[count..])?;
 
-    Ok(computed_hmac.as_slice() == mac)
+    out.truncate(count + rest);
+    Ok(out)
 }
 
-pub struct HMAC<'b> {
-    signer: Box<sign::Signer<'b>>,
+impl<'a> Signer for sign::Signer<'a> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        match sign::Signer::update(self, data) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(From::from(e)),
+        }
+    }
 }
 
-pub fn hmac<F>(key: &HmacKey, cb: F) -> Result<Vec<u8>>
-    where F: Fn(&mut HMAC) -> Result<()> {
-    let pkey = PKey::hmac(key)?;
-    let mut signer = Box::new(sign::Signer::new(MessageDigest::sha256(), &pkey)?);
+/// Incremental decryption, for data we don't want to hold in memory at once
+pub struct Decrypter {
+    crypter: symm::Crypter,
+}
 
-    // Move the value into and out of HMAC so the borrow checker is happy with us.
-    let mut hmac = HMAC { signer };
-    cb(&mut hmac)?;
-    signer = hmac.signer;
+impl Decrypt for Decrypter {
+    fn new(decrypt_key: &[u8], iv: &[u8]) -> Result<Decrypter> {
+        let t = symm::Cipher::aes_256_cbc();
+        let mut crypter = symm::Crypter::new(t, symm::Mode::Decrypt, decrypt_key, Some(iv))?;
+        crypter.pad(false);
 
-    match signer.sign_to_vec() {
-        Ok(x) => Ok(x),
-        Err(e) => Err(From::from(e)),
+        Ok(Decrypter {
+            crypter,
+        })
     }
-}
 
-impl<'b> HMAC<'b> {
-    pub fn update(&mut self, data: &[u8]) -> Result<()> {
-        match self.signer.update(data) {
-            Ok(_) => Ok(()),
+    fn update(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize> {
+        match self.crypter.update(data, out) {
+            Ok(x) => Ok(x),
             Err(e) => Err(From::from(e)),
         }
     }
@@ -0,0 +1,181 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The pure-Rust implementation of our crypto functions, using the RustCrypto
+//! crates.
+
+use super::super::{Result, Error};
+use super::{Backend, Decrypt, Signer};
+#[cfg(feature = "export")]
+use super::ExportBackend;
+use aes::Aes256;
+use cbc;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+#[cfg(feature = "export")]
+use cbc::cipher::{BlockEncrypt, KeyInit, StreamCipher, StreamCipherSeek};
+use cbc::cipher::generic_array::GenericArray;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use pbkdf2::pbkdf2_hmac;
+use getrandom::getrandom;
+use subtle::ConstantTimeEq;
+#[cfg(feature = "export")]
+use chacha20::ChaCha20;
+
+const BLOCK_SIZE: usize = 16;
+
+pub struct RustCrypto;
+
+impl Backend for RustCrypto {
+    type Decrypter = Decrypter;
+
+    fn pbkdf2_sha512(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()> {
+        if iterations == 0 || iterations > u32::MAX as usize {
+            return Err(invalid_length());
+        }
+        pbkdf2_hmac::<Sha512>(pw, salt, iterations as u32, out);
+
+        Ok(())
+    }
+
+    fn sha512(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Sha512::digest(data).to_vec())
+    }
+
+    fn hmac_sha256(key: &[u8], cb: &mut dyn FnMut(&mut dyn Signer) -> Result<()>) -> Result<Vec<u8>> {
+        let mut mac = match <Hmac<Sha256> as Mac>::new_from_slice(key) {
+            Ok(x) => x,
+            Err(_) => return Err(invalid_length()),
+        };
+        cb(&mut mac)?;
+
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn aes256_cbc_encrypt(data: &[u8], encrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(invalid_length());
+        }
+        let mut encryptor = match cbc::Encryptor::<Aes256>::new_from_slices(encrypt_key, iv) {
+            Ok(x) => x,
+            Err(_) => return Err(invalid_length()),
+        };
+
+        let mut out = data.to_vec();
+        for block in out.chunks_exact_mut(BLOCK_SIZE) {
+            encryptor.encrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+
+        Ok(out)
+    }
+
+    fn aes256_cbc_decrypt(data: &[u8], decrypt_key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+        let mut decrypter = Decrypter::new(decrypt_key, iv)?;
+        let mut out = vec![0u8; data.len() + BLOCK_SIZE];
+        let count = decrypter.update(data, &mut out)?;
+
+        out.truncate(count);
+        Ok(out)
+    }
+
+    fn random_bytes(buf: &mut [u8]) -> Result<()> {
+        match getrandom(buf) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::Crypto(super::Error::Random)),
+        }
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.ct_eq(b).into()
+    }
+}
+
+#[cfg(feature = "export")]
+impl ExportBackend for RustCrypto {
+    fn pbkdf2_sha256(pw: &[u8], salt: &[u8], iterations: usize, out: &mut [u8]) -> Result<()> {
+        if iterations == 0 || iterations > u32::MAX as usize {
+            return Err(invalid_length());
+        }
+        pbkdf2_hmac::<Sha256>(pw, salt, iterations as u32, out);
+
+        Ok(())
+    }
+
+    fn sha256(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Sha256::digest(data).to_vec())
+    }
+
+    fn aes256_ecb_encrypt_rounds(data: &mut [u8], key: &[u8], rounds: u64) -> Result<()> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(invalid_length());
+        }
+        let cipher = match Aes256::new_from_slice(key) {
+            Ok(x) => x,
+            Err(_) => return Err(invalid_length()),
+        };
+
+        for block in data.chunks_exact_mut(BLOCK_SIZE) {
+            let block = GenericArray::from_mut_slice(block);
+            for _ in 0..rounds {
+                cipher.encrypt_block(block);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn chacha20_xor(data: &mut [u8], key: &[u8], nonce: &[u8], block: u32) -> Result<()> {
+        let mut cipher = match ChaCha20::new_from_slices(key, nonce) {
+            Ok(x) => x,
+            Err(_) => return Err(invalid_length()),
+        };
+        cipher.seek(u64::from(block) * 64);
+        cipher.apply_keystream(data);
+
+        Ok(())
+    }
+}
+
+fn invalid_length() -> Error {
+    Error::Crypto(super::Error::InvalidLength)
+}
+
+impl Signer for Hmac<Sha256> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        Mac::update(self, data);
+
+        Ok(())
+    }
+}
+
+/// Incremental decryption, for data we don't want to hold in memory at once
+pub struct Decrypter {
+    decryptor: cbc::Decryptor<Aes256>,
+}
+
+impl Decrypt for Decrypter {
+    fn new(decrypt_key: &[u8], iv: &[u8]) -> Result<Decrypter> {
+        match cbc::Decryptor::<Aes256>::new_from_slices(decrypt_key, iv) {
+            Ok(decryptor) => Ok(Decrypter { decryptor }),
+            Err(_) => Err(invalid_length()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) || out.len() < data.len() {
+            return Err(invalid_length());
+        }
+
+        let out = &mut out[..data.len()];
+        out.copy_from_slice(data);
+        for block in out.chunks_exact_mut(BLOCK_SIZE) {
+            self.decryptor.decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+
+        Ok(data.len())
+    }
+}
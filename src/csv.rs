@@ -0,0 +1,95 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Just enough CSV, as in RFC 4180, for exporting and importing items
+
+use std::io::{self, Write};
+use std::mem;
+
+use super::{Result, Error};
+
+/// Write one record, quoting the values which need it
+pub fn write_record<W: Write, S: AsRef<str>>(out: &mut W, values: &[S]) -> io::Result<()> {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        let value = value.as_ref();
+        if value.contains([',', '"', '\n', '\r']) || value.starts_with(' ') || value.ends_with(' ') {
+            write!(out, "\"{}\"", value.replace('"', "\"\""))?;
+        } else {
+            out.write_all(value.as_bytes())?;
+        }
+    }
+
+    out.write_all(b"\r\n")
+}
+
+/// What a value starts with for a spreadsheet to take it as a formula. Values
+/// which start with a quote are escaped as well, so the one we add can be
+/// told apart when importing.
+const FORMULA_STARTS: &[char] = &['=', '+', '-', '@', '\t', '\r', '\''];
+
+/// Put a `'` in front of a value which a spreadsheet would take as a formula
+pub fn escape_formula(value: &mut String) {
+    if value.starts_with(FORMULA_STARTS) {
+        value.insert(0, '\'');
+    }
+}
+
+/// Take out the `'` which `escape_formula` added, if there is one
+pub fn unescape_formula(value: &str) -> &str {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_STARTS) => rest,
+        _ => value,
+    }
+}
+
+/// Split CSV text into records. Quoted values may contain separators, line
+/// breaks and doubled quotes. Blank lines are skipped.
+pub fn read_records(text: &str) -> Result<Vec<Vec<String>>> {
+    let text = text.trim_start_matches('\u{feff}');
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut value = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                value.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if value.is_empty() => quoted = true,
+            _ if quoted => value.push(c),
+            ',' => record.push(mem::take(&mut value)),
+            '\r' | '\n' => {
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                record.push(mem::take(&mut value));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(mem::take(&mut record));
+                }
+                record.clear();
+            }
+            _ => value.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(Error::CsvError);
+    }
+    if !record.is_empty() || !value.is_empty() {
+        record.push(value);
+        records.push(record);
+    }
+
+    Ok(records)
+}
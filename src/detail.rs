@@ -1,4 +1,25 @@
+use std::fmt;
+use std::io;
+
+use serde::{Serialize, Serializer};
 use serde_json as json;
+use zeroize::Zeroize;
+
+use super::{Category, Zeroizing};
+
+/// Counts what's written to it and throws it away
+struct ByteCount(usize);
+
+impl io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum Detail {
@@ -7,26 +28,48 @@ pub enum Detail {
     Generic(Generic),
 }
 
-#[derive(Debug, Deserialize)]
+impl Serialize for Detail {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        // The kind of detail is given by the item's category, so there is no
+        // tag in the data itself.
+        match *self {
+            Detail::Login(ref x) => x.serialize(s),
+            Detail::Password(ref x) => x.serialize(s),
+            Detail::Generic(ref x) => x.serialize(s),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Login {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub html_form: Option<HtmlForm>,
     // These are actually base64 bytes, but I don't know what they mean
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub backup_keys: Vec<String>,
     #[serde(default)]
     pub fields: Vec<LoginField>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<Section>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes_plain: Option<String>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct HtmlForm {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub html_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub html_name: Option<String>,
     pub html_method: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum LoginFieldKind {
     #[serde(rename = "T")]
     Text,
@@ -46,48 +89,243 @@ pub enum LoginFieldKind {
     Radio,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct LoginField {
     #[serde(rename = "type")]
     pub kind: LoginFieldKind,
     pub name: String,
     pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub designation: Option<String>,
 }
 
+impl Detail {
+    /// Empty details of the shape the category needs
+    pub fn new(category: Category) -> Detail {
+        match category {
+            Category::Login => Detail::Login(Login::default()),
+            Category::Password => Detail::Password(Password::default()),
+            _ => Detail::Generic(Generic::default()),
+        }
+    }
+
+    /// Parse the details from a JSON slice. Their shape depends on the
+    /// item's category.
+    pub fn from_slice(category: Category, s: &[u8]) -> json::Result<Detail> {
+        Ok(match category {
+            Category::Login => Detail::Login(json::from_slice(s)?),
+            Category::Password => Detail::Password(json::from_slice(s)?),
+            _ => Detail::Generic(json::from_slice(s)?),
+        })
+    }
+
+    /// Serialize the details into a JSON vector which is wiped when dropped
+    pub fn to_vec(&self) -> json::Result<Zeroizing<Vec<u8>>> {
+        // Size the buffer up front, as growing it would leave copies of the
+        // details behind in memory.
+        let mut len = ByteCount(0);
+        json::to_writer(&mut len, self)?;
+        let mut buf = Zeroizing::new(Vec::with_capacity(len.0));
+        json::to_writer(&mut *buf, self)?;
+
+        Ok(buf)
+    }
+
+    /// Set the notes
+    pub fn set_notes(&mut self, notes: Option<String>) {
+        match *self {
+            Detail::Login(ref mut x) => x.notes_plain = notes,
+            Detail::Password(ref mut x) => x.notes_plain = notes,
+            Detail::Generic(ref mut x) => x.notes_plain = notes,
+        }
+    }
+
+    /// The sections of custom fields
+    pub fn sections(&self) -> &[Section] {
+        match *self {
+            Detail::Login(ref x) => &x.sections,
+            Detail::Password(ref x) => &x.sections,
+            Detail::Generic(ref x) => &x.sections,
+        }
+    }
+
+    /// The notes, in plain text
+    pub fn notes(&self) -> Option<&str> {
+        match *self {
+            Detail::Login(ref x) => x.notes_plain.as_ref(),
+            Detail::Password(ref x) => x.notes_plain.as_ref(),
+            Detail::Generic(ref x) => x.notes_plain.as_ref(),
+        }.map(|s| s.as_str())
+    }
+
+    /// The login's username
+    pub fn username(&self) -> Option<&str> {
+        match *self {
+            Detail::Login(ref x) => x.designated("username"),
+            _ => None,
+        }
+    }
+
+    /// The login's or password item's password
+    pub fn password(&self) -> Option<&str> {
+        match *self {
+            Detail::Login(ref x) => x.designated("password"),
+            Detail::Password(ref x) => Some(&x.password),
+            Detail::Generic(_) => None,
+        }
+    }
+
+    /// Look up the value of a field. This is either a login field's name, or
+    /// a custom field's name or title. Custom fields can be qualified with
+    /// their section's name or title as `section.field`. Names and titles are
+    /// compared without regard to case.
+    pub fn field(&self, path: &str) -> Option<String> {
+        if let Detail::Login(ref login) = *self {
+            if let Some(field) = login.fields.iter().find(|f| f.name.eq_ignore_ascii_case(path)) {
+                return Some(field.value.clone());
+            }
+        }
+
+        let matches = |name: &str, title: &str, wanted: &str| {
+            name.eq_ignore_ascii_case(wanted) || title.eq_ignore_ascii_case(wanted)
+        };
+        let (section, name) = match path.find('.') {
+            Some(pos) => (Some(&path[..pos]), &path[pos + 1..]),
+            None => (None, path),
+        };
+        self.sections().iter()
+            .filter(|s| section.is_none_or(|wanted| matches(&s.name, &s.title, wanted)))
+            .flat_map(|s| s.fields.iter())
+            .find(|f| matches(&f.name, f.title.as_deref().unwrap_or(""), name))
+            .and_then(|f| f.value.as_ref())
+            .map(|v| v.to_string())
+    }
+
+    /// Set the value of a custom field, found the way `field` finds them.
+    /// The field, and the section if the path names one, are added if they
+    /// don't exist yet.
+    pub fn set_field(&mut self, path: &str, kind: FieldKind, value: String) {
+        let (section, name) = match path.find('.') {
+            Some(pos) => (&path[..pos], &path[pos + 1..]),
+            None => ("", path),
+        };
+        let sections = match *self {
+            Detail::Login(ref mut x) => &mut x.sections,
+            Detail::Password(ref mut x) => &mut x.sections,
+            Detail::Generic(ref mut x) => &mut x.sections,
+        };
+
+        let found = sections.iter().position(|s| {
+            s.name.eq_ignore_ascii_case(section) || (!section.is_empty() && s.title.eq_ignore_ascii_case(section))
+        });
+        let pos = match found {
+            Some(pos) => pos,
+            None => {
+                sections.push(Section { name: section.to_string(), title: section.to_string(), ..Section::default() });
+                sections.len() - 1
+            }
+        };
+        let fields = &mut sections[pos].fields;
+        let found = fields.iter_mut().find(|f| {
+            f.name.eq_ignore_ascii_case(name) || f.title.as_deref().is_some_and(|t| t.eq_ignore_ascii_case(name))
+        });
+        if let Some(field) = found {
+            if let Some(FieldValue::String(ref mut old)) = field.value {
+                old.zeroize();
+            }
+            field.kind = kind;
+            field.value = Some(FieldValue::String(value));
+            return;
+        }
+
+        fields.push(Field {
+            kind,
+            name: name.to_string(),
+            value: Some(FieldValue::String(value)),
+            attr: None,
+            title: Some(name.to_string()),
+            extra: json::Map::new(),
+        });
+    }
+}
+
 impl Login {
     // Parse a login object from a JSON slice
     pub fn from_slice(s: &[u8]) -> json::Result<Self> {
         json::from_slice(s)
     }
+
+    /// The value of the field with this designation, i.e. "username" or
+    /// "password"
+    pub fn designated(&self, designation: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|f| f.designation.as_deref() == Some(designation))
+            .map(|f| f.value.as_str())
+    }
+
+    /// Set the value of the field with this designation, adding the field if
+    /// there isn't one.
+    pub fn set_designated(&mut self, designation: &str, value: String) {
+        if let Some(field) = self.fields.iter_mut().find(|f| f.designation.as_deref() == Some(designation)) {
+            field.value.zeroize();
+            field.value = value;
+            return;
+        }
+
+        let kind = if designation == "password" { LoginFieldKind::Password } else { LoginFieldKind::Text };
+        self.fields.push(LoginField {
+            kind,
+            name: designation.to_string(),
+            value,
+            designation: Some(designation.to_string()),
+        });
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Password {
     // These are actually base64 bytes, but I don't know what they mean
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub backup_keys: Vec<String>,
     pub password: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<Section>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes_plain: Option<String>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Generic {
     #[serde(default)]
     pub sections: Vec<Section>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub notes_plain: Option<String>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Section {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub title: String,
+    // Sections without any fields may leave them out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub fields: Vec<Field>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum FieldKind {
     String,
@@ -104,7 +342,7 @@ pub enum FieldKind {
     URL
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum FieldValue {
     String(String),
@@ -112,7 +350,7 @@ pub enum FieldValue {
     I64(i64),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Field {
     #[serde(rename = "k")]
@@ -120,26 +358,43 @@ pub struct Field {
     #[serde(rename = "n")]
     pub name: String,
     #[serde(rename = "v", default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub value: Option<FieldValue>,
     #[serde(rename = "a")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub attr: Option<Attributes>,
+    /// The title the official clients show for the field
+    #[serde(rename = "t", default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Address {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub zip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub street: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Attributes {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub guarded: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub clipboard_filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub generate: Option<String>,
 }
 
@@ -149,3 +404,171 @@ impl Generic {
         json::from_slice(s)
     }
 }
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldValue::String(ref s) => write!(f, "{}", s),
+            FieldValue::I64(n) => write!(f, "{}", n),
+            FieldValue::Address(ref a) => {
+                let parts = [&a.street, &a.city, &a.state, &a.zip, &a.country];
+                let parts: Vec<&str> = parts.iter()
+                    .filter_map(|p| p.as_deref())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                write!(f, "{}", parts.join(", "))
+            }
+        }
+    }
+}
+
+// The secret values are left out of the debug output so they don't end up in
+// logs by accident.
+
+impl fmt::Debug for LoginField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value: &dyn fmt::Debug = if self.kind == LoginFieldKind::Password { &".." } else { &self.value };
+        f.debug_struct("LoginField")
+            .field("kind", &self.kind)
+            .field("name", &self.name)
+            .field("value", value)
+            .field("designation", &self.designation)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Password")
+            .field("backup_keys", &self.backup_keys)
+            .field("password", &"..")
+            .field("sections", &self.sections)
+            .field("notes_plain", &self.notes_plain)
+            .field("extra", &self.extra)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value: &dyn fmt::Debug = if self.kind == FieldKind::Concealed { &".." } else { &self.value };
+        f.debug_struct("Field")
+            .field("kind", &self.kind)
+            .field("name", &self.name)
+            .field("value", value)
+            .field("attr", &self.attr)
+            .field("title", &self.title)
+            .field("extra", &self.extra)
+            .finish()
+    }
+}
+
+// Wiping the details. `Item::detail` returns them wrapped in `Zeroizing` so
+// this happens when they're dropped.
+
+impl Zeroize for Detail {
+    fn zeroize(&mut self) {
+        match *self {
+            Detail::Login(ref mut x) => x.zeroize(),
+            Detail::Password(ref mut x) => x.zeroize(),
+            Detail::Generic(ref mut x) => x.zeroize(),
+        }
+    }
+}
+
+impl Zeroize for Login {
+    fn zeroize(&mut self) {
+        self.backup_keys.zeroize();
+        for field in &mut self.fields {
+            field.zeroize();
+        }
+        for section in &mut self.sections {
+            section.zeroize();
+        }
+        self.notes_plain.zeroize();
+        zeroize_map(&mut self.extra);
+    }
+}
+
+impl Zeroize for LoginField {
+    fn zeroize(&mut self) {
+        self.name.zeroize();
+        self.value.zeroize();
+        self.designation.zeroize();
+    }
+}
+
+impl Zeroize for Password {
+    fn zeroize(&mut self) {
+        self.backup_keys.zeroize();
+        self.password.zeroize();
+        for section in &mut self.sections {
+            section.zeroize();
+        }
+        self.notes_plain.zeroize();
+        zeroize_map(&mut self.extra);
+    }
+}
+
+impl Zeroize for Generic {
+    fn zeroize(&mut self) {
+        for section in &mut self.sections {
+            section.zeroize();
+        }
+        self.notes_plain.zeroize();
+        zeroize_map(&mut self.extra);
+    }
+}
+
+impl Zeroize for Section {
+    fn zeroize(&mut self) {
+        for field in &mut self.fields {
+            field.zeroize();
+        }
+        zeroize_map(&mut self.extra);
+    }
+}
+
+impl Zeroize for Field {
+    fn zeroize(&mut self) {
+        self.name.zeroize();
+        match self.value {
+            Some(FieldValue::String(ref mut x)) => x.zeroize(),
+            Some(FieldValue::Address(ref mut x)) => x.zeroize(),
+            Some(FieldValue::I64(ref mut x)) => x.zeroize(),
+            None => (),
+        }
+        self.title.zeroize();
+        zeroize_map(&mut self.extra);
+    }
+}
+
+impl Zeroize for Address {
+    fn zeroize(&mut self) {
+        self.city.zeroize();
+        self.zip.zeroize();
+        self.state.zeroize();
+        self.country.zeroize();
+        self.street.zeroize();
+    }
+}
+
+fn zeroize_map(m: &mut json::Map<String, json::Value>) {
+    for v in m.values_mut() {
+        zeroize_value(v);
+    }
+}
+
+/// Wipe the strings in a JSON value
+pub fn zeroize_value(v: &mut json::Value) {
+    match *v {
+        json::Value::String(ref mut s) => s.zeroize(),
+        json::Value::Array(ref mut a) => {
+            for x in a.iter_mut() {
+                zeroize_value(x);
+            }
+        },
+        json::Value::Object(ref mut m) => zeroize_map(m),
+        _ => (),
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A writer for Bitwarden's JSON exports. The password-protected variant
+//! derives a key with PBKDF2-SHA256, stretches it with HKDF into encryption
+//! and MAC keys and stores the plain export as an AES-256-CBC `EncString`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use base64;
+use serde_json::{self, Value};
+
+use super::super::{Result, Item, Category, Detail, Field, FieldKind, FieldValue, LoginFieldKind, Uuid, UnlockedVault, Zeroizing};
+use super::super::crypto;
+use super::super::detail::Address;
+
+/// The PBKDF2 iterations for password-protected exports, Bitwarden's default
+pub const KDF_ITERATIONS: usize = 600_000;
+
+const TYPE_LOGIN: u32 = 1;
+const TYPE_SECURE_NOTE: u32 = 2;
+const TYPE_CARD: u32 = 3;
+const TYPE_IDENTITY: u32 = 4;
+
+const FIELD_TEXT: u32 = 0;
+const FIELD_HIDDEN: u32 = 1;
+
+/// Write the vault as a Bitwarden JSON export. With a password, the export is
+/// encrypted the way Bitwarden's password-protected exports are. Logins and
+/// passwords become logins, credit cards become cards, identities become
+/// identities and the other categories secure notes. Fields which don't have
+/// a place in Bitwarden's items become custom fields. Trashed items are left
+/// out, as Bitwarden doesn't export its own.
+pub fn write_bitwarden<W: Write>(vault: &UnlockedVault, password: Option<&[u8]>, mut out: W) -> Result<()> {
+    let titles = super::folder_titles(vault)?;
+    let mut folders: Vec<(&Uuid, &String)> = titles.iter().collect();
+    folders.sort_by(|a, b| a.1.cmp(b.1));
+    let folders: Vec<Value> = folders.into_iter().map(|(uuid, title)| json!({ "id": id(uuid), "name": title })).collect();
+
+    let mut items = Vec::new();
+    for item in super::items(vault).iter().filter(|item| !item.is_trashed()) {
+        items.push(item_entry(item, &titles)?);
+    }
+
+    let export = Zeroizing::new(serde_json::to_string_pretty(&json!({
+        "encrypted": false,
+        "folders": folders,
+        "items": items,
+    }))?);
+
+    match password {
+        None => out.write_all(export.as_bytes())?,
+        Some(password) => {
+            let encrypted = encrypt_export(&export, password)?;
+            serde_json::to_writer_pretty(&mut out, &encrypted)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn encrypt_export(export: &str, password: &[u8]) -> Result<Value> {
+    let mut salt = [0u8; 16];
+    crypto::random_bytes(&mut salt)?;
+    // Bitwarden uses the text of the salt, not the bytes it encodes
+    let salt = base64::encode(&salt);
+    let key = crypto::pbkdf2_sha256(password, salt.as_bytes(), KDF_ITERATIONS)?;
+
+    // HKDF-Expand with a single block of output for each key
+    let enc_key = Zeroizing::new(crypto::hmac(&key, |s| s.update(b"enc\x01"))?);
+    let mac_key = Zeroizing::new(crypto::hmac(&key, |s| s.update(b"mac\x01"))?);
+
+    // Bitwarden checks the password against this before decrypting the data
+    let validation = crypto::random_uuid()?.to_hyphenated().to_string();
+
+    Ok(json!({
+        "encrypted": true,
+        "passwordProtected": true,
+        "salt": salt,
+        "kdfType": 0,
+        "kdfIterations": KDF_ITERATIONS,
+        "encKeyValidation_DO_NOT_EDIT": enc_string(validation.as_bytes(), &enc_key, &mac_key)?,
+        "data": enc_string(export.as_bytes(), &enc_key, &mac_key)?,
+    }))
+}
+
+/// Encrypt the data as a type 2 `EncString`: AES-256-CBC with an
+/// HMAC-SHA256 over the IV and ciphertext.
+fn enc_string(data: &[u8], enc_key: &[u8], mac_key: &[u8]) -> Result<String> {
+    let mut iv = [0u8; 16];
+    crypto::random_bytes(&mut iv)?;
+    let mut padded = Zeroizing::new(data.to_vec());
+    super::pad(&mut padded);
+    let encrypted = crypto::encrypt_data(&padded, enc_key, &iv)?;
+    let mac = crypto::hmac(mac_key, |s| {
+        s.update(&iv)?;
+        s.update(&encrypted)
+    })?;
+
+    Ok(format!("2.{}|{}|{}", base64::encode(&iv), base64::encode(&encrypted), base64::encode(&mac)))
+}
+
+fn id(uuid: &Uuid) -> String {
+    uuid.to_hyphenated().to_string()
+}
+
+/// The fields in an item's sections, by name, with those which were given a
+/// place in the Bitwarden item marked as used
+struct Fields<'a> {
+    fields: Vec<&'a Field>,
+    used: HashSet<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(detail: &'a Detail) -> Fields<'a> {
+        Fields {
+            fields: detail.sections().iter().flat_map(|s| s.fields.iter()).collect(),
+            used: HashSet::new(),
+        }
+    }
+
+    fn find(&mut self, name: &str) -> Option<&'a Field> {
+        let field = self.fields.iter().find(|f| f.name == name && f.value.is_some())?;
+        self.used.insert(&field.name);
+        Some(*field)
+    }
+
+    /// Take the field's value as text
+    fn take(&mut self, name: &str) -> Value {
+        match self.find(name).and_then(|f| f.value.as_ref()) {
+            Some(value) => Value::from(value.to_string()),
+            None => Value::Null,
+        }
+    }
+
+    /// The fields which are left, as Bitwarden custom fields
+    fn rest(&self) -> Vec<Value> {
+        self.fields.iter()
+            .filter(|f| !self.used.contains(f.name.as_str()))
+            .filter_map(|f| f.value.as_ref().map(|v| (f, v.to_string())))
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(f, v)| {
+                let kind = if f.kind == FieldKind::Concealed { FIELD_HIDDEN } else { FIELD_TEXT };
+                json!({ "name": f.title.as_deref().unwrap_or(&f.name), "value": v, "type": kind, "linkedId": null })
+            })
+            .collect()
+    }
+}
+
+fn item_entry(item: &Item, folders: &HashMap<Uuid, String>) -> Result<Value> {
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+    let mut fields = Fields::new(&detail);
+    let mut custom = Vec::new();
+
+    let mut entry = json!({
+        "id": id(&item.uuid),
+        "organizationId": null,
+        "folderId": item.folder.filter(|f| folders.contains_key(f)).map(|f| id(&f)),
+        "type": TYPE_SECURE_NOTE,
+        "reprompt": 0,
+        "name": overview.title.as_deref().unwrap_or(""),
+        "notes": detail.notes(),
+        "favorite": item.fave.is_some(),
+        "collectionIds": null,
+    });
+
+    match item.category {
+        Category::Login | Category::Password => {
+            let mut urls: Vec<&String> = overview.url.iter().chain(overview.urls.iter().map(|u| &u.u)).collect();
+            urls.dedup();
+            let uris: Vec<Value> = urls.into_iter().map(|u| json!({ "match": null, "uri": u })).collect();
+            // 1Password keeps one-time password secrets in section fields
+            // with this prefix.
+            let totp = fields.fields.iter().find(|f| f.name.starts_with("TOTP_")).map(|f| f.name.clone());
+            let totp = totp.map(|name| fields.take(&name)).unwrap_or(Value::Null);
+
+            let (mut username, mut password) = (Value::Null, Value::Null);
+            match *detail {
+                Detail::Login(ref login) => {
+                    for field in &login.fields {
+                        match field.designation.as_deref() {
+                            Some("username") => username = Value::from(field.value.as_str()),
+                            Some("password") => password = Value::from(field.value.as_str()),
+                            _ if field.value.is_empty() || field.kind == LoginFieldKind::Button => (),
+                            _ => {
+                                let kind = if field.kind == LoginFieldKind::Password { FIELD_HIDDEN } else { FIELD_TEXT };
+                                custom.push(json!({ "name": field.name, "value": field.value, "type": kind, "linkedId": null }));
+                            }
+                        }
+                    }
+                }
+                Detail::Password(ref p) => password = Value::from(p.password.as_str()),
+                Detail::Generic(_) => (),
+            }
+
+            entry["type"] = Value::from(TYPE_LOGIN);
+            entry["login"] = json!({ "uris": uris, "username": username, "password": password, "totp": totp });
+        }
+        Category::CreditCard => {
+            let (month, year) = match fields.find("expiry").and_then(|f| f.value.as_ref()) {
+                Some(value) => expiry(value),
+                None => (Value::Null, Value::Null),
+            };
+            let brand = match fields.take("type") {
+                Value::String(ref s) => Value::from(card_brand(s)),
+                other => other,
+            };
+            entry["type"] = Value::from(TYPE_CARD);
+            entry["card"] = json!({
+                "cardholderName": fields.take("cardholder"),
+                "brand": brand,
+                "number": fields.take("ccnum"),
+                "expMonth": month,
+                "expYear": year,
+                "code": fields.take("cvv"),
+            });
+        }
+        Category::Identity => {
+            let address = match fields.find("address").and_then(|f| f.value.as_ref()) {
+                Some(FieldValue::Address(a)) => Some(a),
+                _ => None,
+            };
+            let part = |get: fn(&Address) -> &Option<String>| {
+                address.and_then(|a| get(a).clone()).map(Value::from).unwrap_or(Value::Null)
+            };
+            entry["type"] = Value::from(TYPE_IDENTITY);
+            entry["identity"] = json!({
+                "title": null,
+                "firstName": fields.take("firstname"),
+                "middleName": fields.take("initial"),
+                "lastName": fields.take("lastname"),
+                "address1": part(|a| &a.street),
+                "address2": null,
+                "address3": null,
+                "city": part(|a| &a.city),
+                "state": part(|a| &a.state),
+                "postalCode": part(|a| &a.zip),
+                "country": part(|a| &a.country),
+                "company": fields.take("company"),
+                "email": fields.take("email"),
+                "phone": fields.take("defphone"),
+                "ssn": null,
+                "username": fields.take("username"),
+                "passportNumber": null,
+                "licenseNumber": null,
+            });
+        }
+        _ => entry["secureNote"] = json!({ "type": 0 }),
+    }
+
+    custom.extend(fields.rest());
+    if !custom.is_empty() {
+        entry["fields"] = Value::from(custom);
+    }
+
+    Ok(entry)
+}
+
+/// Split a 1Password month and year such as 201905 into Bitwarden's month and
+/// year
+fn expiry(value: &FieldValue) -> (Value, Value) {
+    let n = match *value {
+        FieldValue::I64(n) => n,
+        ref other => match other.to_string().parse() {
+            Ok(n) => n,
+            Err(_) => return (Value::Null, Value::Null),
+        },
+    };
+
+    (Value::from((n % 100).to_string()), Value::from((n / 100).to_string()))
+}
+
+fn card_brand(kind: &str) -> &str {
+    match kind {
+        "visa" => "Visa",
+        "mc" | "mastercard" => "Mastercard",
+        "amex" => "Amex",
+        "diners" => "Diners Club",
+        "discover" => "Discover",
+        "jcb" => "JCB",
+        "maestro" => "Maestro",
+        "unionpay" => "UnionPay",
+        other => other,
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use super::super::{Result, Error, Item, Category, Detail, Overview, Uuid, UnlockedVault, Zeroizing};
+use super::super::csv::{write_record, escape_formula};
+
+/// What goes in a column of a CSV export
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvColumn {
+    Title,
+    Username,
+    Password,
+    Url,
+    Notes,
+    /// The title of the item's folder
+    Folder,
+    /// The tags, separated by commas
+    Tags,
+    /// 1 for favourites, 0 for the rest
+    Favorite,
+    /// The secret for one-time passwords
+    Totp,
+    /// The category's code, as in `Category::from_str`
+    Category,
+    /// Any other field, by name or title, optionally as `section.field`
+    Field(String),
+}
+
+impl FromStr for CsvColumn {
+    type Err = Error;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "" => return Err(Error::CsvError),
+            "title" => CsvColumn::Title,
+            "username" => CsvColumn::Username,
+            "password" => CsvColumn::Password,
+            "url" => CsvColumn::Url,
+            "notes" => CsvColumn::Notes,
+            "folder" => CsvColumn::Folder,
+            "tags" => CsvColumn::Tags,
+            "favorite" => CsvColumn::Favorite,
+            "totp" => CsvColumn::Totp,
+            "category" => CsvColumn::Category,
+            _ => CsvColumn::Field(s.trim().to_string()),
+        })
+    }
+}
+
+/// The columns of a CSV export and their headers
+#[derive(Debug, Clone)]
+pub struct CsvLayout {
+    pub columns: Vec<(String, CsvColumn)>,
+    /// Leave out everything but logins and passwords, for importers which
+    /// only know about those
+    pub logins_only: bool,
+    /// Put a `'` in front of values which a spreadsheet would take as a
+    /// formula. Layouts from `parse` do this, as they're for reading in a
+    /// spreadsheet, but the presets don't, as their importers would keep it.
+    pub escape_formulas: bool,
+}
+
+impl CsvLayout {
+    /// Parse a comma-separated list of columns. Each is one of `title`,
+    /// `username`, `password`, `url`, `notes`, `folder`, `tags`, `favorite`,
+    /// `totp`, `category` or a field's path, and may be preceded by its
+    /// header as in `Server=server.url`. Otherwise the header is the column's
+    /// name.
+    pub fn parse(spec: &str) -> Result<CsvLayout> {
+        let mut columns = Vec::new();
+        for column in spec.split(',') {
+            let (header, name) = match column.find('=') {
+                Some(pos) => (&column[..pos], &column[pos + 1..]),
+                None => (column, column),
+            };
+            columns.push((header.trim().to_string(), name.parse()?));
+        }
+
+        Ok(CsvLayout { columns, logins_only: false, escape_formulas: true })
+    }
+
+    /// What Chrome's password manager imports
+    pub fn chrome() -> CsvLayout {
+        CsvLayout::preset(&[("name", CsvColumn::Title), ("url", CsvColumn::Url), ("username", CsvColumn::Username),
+                            ("password", CsvColumn::Password), ("note", CsvColumn::Notes)])
+    }
+
+    /// What Firefox imports
+    pub fn firefox() -> CsvLayout {
+        CsvLayout::preset(&[("url", CsvColumn::Url), ("username", CsvColumn::Username), ("password", CsvColumn::Password)])
+    }
+
+    /// LastPass' own export format, which many password managers import
+    pub fn lastpass() -> CsvLayout {
+        let mut layout = CsvLayout::preset(&[("url", CsvColumn::Url), ("username", CsvColumn::Username),
+                                             ("password", CsvColumn::Password), ("totp", CsvColumn::Totp),
+                                             ("extra", CsvColumn::Notes), ("name", CsvColumn::Title),
+                                             ("grouping", CsvColumn::Folder), ("fav", CsvColumn::Favorite)]);
+        // LastPass keeps notes as items without a URL
+        layout.logins_only = false;
+        layout
+    }
+
+    fn preset(columns: &[(&str, CsvColumn)]) -> CsvLayout {
+        CsvLayout {
+            columns: columns.iter().map(|(header, column)| (header.to_string(), column.clone())).collect(),
+            logins_only: true,
+            escape_formulas: false,
+        }
+    }
+}
+
+/// Write the vault's items as CSV with the given columns, one row per item
+/// after a row of headers. Trashed items are left out.
+pub fn write_csv<W: Write>(vault: &UnlockedVault, layout: &CsvLayout, mut out: W) -> Result<()> {
+    let folders = super::folder_titles(vault)?;
+    let headers: Vec<&str> = layout.columns.iter().map(|(header, _)| header.as_str()).collect();
+    write_record(&mut out, &headers)?;
+
+    for item in super::items(vault).iter().filter(|item| !item.is_trashed()) {
+        if layout.logins_only && item.category != Category::Login && item.category != Category::Password {
+            continue;
+        }
+        let overview = item.overview()?;
+        let detail = item.detail()?;
+        let mut row = Vec::new();
+        for (_, column) in &layout.columns {
+            let mut value = Zeroizing::new(value(item, &overview, &detail, column, &folders));
+            if layout.escape_formulas {
+                escape_formula(&mut value);
+            }
+            row.push(value);
+        }
+        let row: Vec<&str> = row.iter().map(|v| v.as_str()).collect();
+        write_record(&mut out, &row)?;
+    }
+
+    Ok(())
+}
+
+fn value(item: &Item, overview: &Overview, detail: &Detail, column: &CsvColumn, folders: &HashMap<Uuid, String>) -> String {
+    match *column {
+        CsvColumn::Title => overview.title.clone().unwrap_or_default(),
+        CsvColumn::Url => overview.url.clone().or_else(|| overview.urls.first().map(|u| u.u.clone())).unwrap_or_default(),
+        CsvColumn::Username => detail.username().map(|s| s.to_string()).unwrap_or_default(),
+        CsvColumn::Password => detail.password().map(|s| s.to_string()).unwrap_or_default(),
+        CsvColumn::Notes => detail.notes().map(|s| s.to_string()).unwrap_or_default(),
+        CsvColumn::Folder => item.folder.and_then(|f| folders.get(&f)).cloned().unwrap_or_default(),
+        CsvColumn::Tags => overview.tags.join(","),
+        CsvColumn::Favorite => if item.fave.is_some() { "1" } else { "0" }.to_string(),
+        CsvColumn::Totp => detail.sections().iter()
+            .flat_map(|s| s.fields.iter())
+            .find(|f| f.name.starts_with("TOTP_"))
+            .and_then(|f| f.value.as_ref())
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+        CsvColumn::Category => item.category.code().to_string(),
+        CsvColumn::Field(ref path) => detail.field(path).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A writer for KeePass' KDBX 4 format. The database is protected with the
+//! password alone, using AES-KDF to derive the key, AES-256-CBC for the outer
+//! encryption and ChaCha20 for the protected values inside it.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+
+use base64;
+
+use super::super::{Result, Item, Category, Detail, FieldKind, LoginFieldKind, Uuid, UnlockedVault, Zeroizing};
+use super::super::crypto;
+
+const SIGNATURE: [u8; 8] = [0x03, 0xd9, 0xa2, 0x9a, 0x67, 0xfb, 0x4b, 0xb5];
+const VERSION: u32 = 0x0004_0000;
+
+const CIPHER_AES256_CBC: [u8; 16] = [0x31, 0xc1, 0xf2, 0xe6, 0xbf, 0x71, 0x43, 0x50, 0xbe, 0x58, 0x05, 0x21, 0x6a, 0xfc, 0x5a, 0xff];
+const KDF_AES: [u8; 16] = [0xc9, 0xd9, 0xf3, 0x9a, 0x62, 0x8a, 0x44, 0x60, 0xbf, 0x74, 0x0d, 0x08, 0xc1, 0x8a, 0x4f, 0xea];
+const INNER_STREAM_CHACHA20: u32 = 3;
+
+/// How often AES-KDF encrypts the key. KeePass can raise this after the
+/// import.
+pub const AES_KDF_ROUNDS: u64 = 100_000;
+
+/// The size of the HMAC-protected blocks the encrypted payload is split into
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// Seconds between 0001-01-01, where KDBX 4 counts time from, and the Unix
+/// epoch
+const EPOCH_OFFSET: i64 = 62_135_596_800;
+
+// Outer header fields
+const HEADER_END: u8 = 0;
+const HEADER_CIPHER: u8 = 2;
+const HEADER_COMPRESSION: u8 = 3;
+const HEADER_MASTER_SEED: u8 = 4;
+const HEADER_IV: u8 = 7;
+const HEADER_KDF: u8 = 11;
+
+// Inner header fields
+const INNER_END: u8 = 0;
+const INNER_STREAM_ID: u8 = 1;
+const INNER_STREAM_KEY: u8 = 2;
+const INNER_BINARY: u8 = 3;
+
+/// Write the vault as a KeePass KDBX 4 database protected by the password.
+/// Folders become groups, trashed items go into the recycle bin and each
+/// category gets an entry template. Custom fields become string fields,
+/// which are protected if they're concealed, and attachments become binaries.
+pub fn write_kdbx<W: Write>(vault: &UnlockedVault, password: &[u8], mut out: W) -> Result<()> {
+    let mut master_seed = [0u8; 32];
+    let mut kdf_seed = [0u8; 32];
+    let mut iv = [0u8; 16];
+    let mut stream_key = Zeroizing::new([0u8; 64]);
+    crypto::random_bytes(&mut master_seed)?;
+    crypto::random_bytes(&mut kdf_seed)?;
+    crypto::random_bytes(&mut iv)?;
+    crypto::random_bytes(&mut stream_key[..])?;
+
+    // The outer header, which is in the clear but covered by the HMAC
+    let mut kdf = Vec::new();
+    // Version 1.0 of the dictionary format
+    kdf.extend_from_slice(&[0x00, 0x01]);
+    variant(&mut kdf, 0x42, "$UUID", &KDF_AES);
+    variant(&mut kdf, 0x05, "R", &AES_KDF_ROUNDS.to_le_bytes());
+    variant(&mut kdf, 0x42, "S", &kdf_seed);
+    kdf.push(0);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&SIGNATURE);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header_field(&mut header, HEADER_CIPHER, &CIPHER_AES256_CBC);
+    header_field(&mut header, HEADER_COMPRESSION, &0u32.to_le_bytes());
+    header_field(&mut header, HEADER_MASTER_SEED, &master_seed);
+    header_field(&mut header, HEADER_IV, &iv);
+    header_field(&mut header, HEADER_KDF, &kdf);
+    header_field(&mut header, HEADER_END, b"\r\n\r\n");
+
+    // The key is the password hashed twice, run through AES-KDF and hashed
+    // together with the master seed.
+    let composite = Zeroizing::new(crypto::hash_sha256(&crypto::hash_sha256(password)?)?);
+    let mut transformed = Zeroizing::new(composite.to_vec());
+    crypto::aes_kdf(&mut transformed, &kdf_seed, AES_KDF_ROUNDS)?;
+    let transformed = Zeroizing::new(crypto::hash_sha256(&transformed)?);
+
+    let mut seeded = Zeroizing::new(master_seed.to_vec());
+    seeded.extend_from_slice(&transformed);
+    let encryption_key = Zeroizing::new(crypto::hash_sha256(&seeded)?);
+    seeded.push(1);
+    let hmac_base = Zeroizing::new(crypto::hash_sha512(&seeded)?);
+
+    // The payload is the inner header followed by the XML document
+    let mut stream = InnerStream::new(&stream_key[..])?;
+    let mut binaries = Vec::new();
+    let xml = document(vault, &mut stream, &mut binaries)?;
+
+    // Sized up front, as growing it would leave copies of the attachments
+    // and the document behind. Each header field has five bytes before its
+    // data and the padding is at most a block.
+    let binaries_size: usize = binaries.iter().map(|b| 5 + 1 + b.len()).sum();
+    let size = 5 + 4 + 5 + stream_key.len() + binaries_size + 5 + xml.len() + 16;
+    let mut payload = Zeroizing::new(Vec::with_capacity(size));
+    header_field(&mut payload, INNER_STREAM_ID, &INNER_STREAM_CHACHA20.to_le_bytes());
+    header_field(&mut payload, INNER_STREAM_KEY, &stream_key[..]);
+    for binary in &binaries {
+        // A leading zero flags the binary as not needing memory protection
+        let mut data = Zeroizing::new(Vec::with_capacity(binary.len() + 1));
+        data.push(0);
+        data.extend_from_slice(binary);
+        header_field(&mut payload, INNER_BINARY, &data);
+    }
+    header_field(&mut payload, INNER_END, &[]);
+    payload.extend_from_slice(xml.as_bytes());
+
+    super::pad(&mut payload);
+    debug_assert!(payload.len() <= size);
+    let encrypted = crypto::encrypt_data(&payload, &encryption_key, &iv)?;
+
+    out.write_all(&header)?;
+    out.write_all(&crypto::hash_sha256(&header)?)?;
+    let header_key = block_key(&hmac_base, u64::MAX)?;
+    out.write_all(&crypto::hmac(&header_key, |s| s.update(&header))?)?;
+
+    // An empty block marks the end
+    for (index, chunk) in encrypted.chunks(BLOCK_SIZE).chain(Some(&[][..])).enumerate() {
+        write_block(&mut out, &hmac_base, index as u64, chunk)?;
+    }
+
+    Ok(())
+}
+
+fn header_field(buf: &mut Vec<u8>, id: u8, data: &[u8]) {
+    buf.push(id);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// An entry of a KDBX variant dictionary, which is how the KDF parameters are
+/// stored
+fn variant(buf: &mut Vec<u8>, kind: u8, name: &str, value: &[u8]) {
+    buf.push(kind);
+    buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+/// The HMAC key for one block of the payload. The header uses the last index.
+fn block_key(hmac_base: &[u8], index: u64) -> Result<Zeroizing<Vec<u8>>> {
+    let mut data = Zeroizing::new(index.to_le_bytes().to_vec());
+    data.extend_from_slice(hmac_base);
+
+    Ok(Zeroizing::new(crypto::hash_sha512(&data)?))
+}
+
+fn write_block<W: Write>(out: &mut W, hmac_base: &[u8], index: u64, data: &[u8]) -> Result<()> {
+    let size = (data.len() as u32).to_le_bytes();
+    let key = block_key(hmac_base, index)?;
+    let mac = crypto::hmac(&key, |s| {
+        s.update(&index.to_le_bytes())?;
+        s.update(&size)?;
+        s.update(data)
+    })?;
+
+    out.write_all(&mac)?;
+    out.write_all(&size)?;
+    out.write_all(data)?;
+
+    Ok(())
+}
+
+/// The ChaCha20 stream the protected values are encrypted with, in the order
+/// they appear in the document
+#[derive(Clone)]
+struct InnerStream {
+    key: Zeroizing<Vec<u8>>,
+    nonce: Zeroizing<Vec<u8>>,
+    position: u64,
+}
+
+impl InnerStream {
+    fn new(stream_key: &[u8]) -> Result<InnerStream> {
+        let hash = Zeroizing::new(crypto::hash_sha512(stream_key)?);
+        Ok(InnerStream {
+            key: Zeroizing::new(hash[..32].to_vec()),
+            nonce: Zeroizing::new(hash[32..44].to_vec()),
+            position: 0,
+        })
+    }
+
+    fn protect(&mut self, value: &str) -> Result<String> {
+        // Start at the beginning of the current keystream block and throw
+        // away what was used up already.
+        let offset = (self.position % 64) as usize;
+        let mut buf = Zeroizing::new(vec![0u8; offset + value.len()]);
+        buf[offset..].copy_from_slice(value.as_bytes());
+        crypto::chacha20_xor(&mut buf, &self.key, &self.nonce, (self.position / 64) as u32)?;
+        self.position += value.len() as u64;
+
+        Ok(base64::encode(&buf[offset..]))
+    }
+}
+
+/// A string field of an entry
+struct StringField {
+    key: String,
+    value: Zeroizing<String>,
+    protected: bool,
+}
+
+fn add_string(fields: &mut Vec<StringField>, key: &str, value: String, protected: bool) {
+    // Keys have to be unique within an entry
+    let mut unique = key.to_string();
+    let mut n = 1;
+    while fields.iter().any(|f| f.key == unique) {
+        n += 1;
+        unique = format!("{} ({})", key, n);
+    }
+    fields.push(StringField { key: unique, value: Zeroizing::new(value), protected });
+}
+
+fn set_string(fields: &mut [StringField], key: &str, value: String) {
+    if let Some(field) = fields.iter_mut().find(|f| f.key == key) {
+        field.value = Zeroizing::new(value);
+    }
+}
+
+/// Counts what's written, to size the buffer for the document
+struct ByteCount(usize);
+
+impl FmtWrite for ByteCount {
+    fn write_str(&mut self, s: &str) -> ::std::fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// An entry before it's written out
+struct Entry {
+    uuid: Uuid,
+    category: Category,
+    created: i64,
+    updated: i64,
+    tags: Vec<String>,
+    strings: Vec<StringField>,
+    /// Attachment names and their index in the list of binaries
+    binaries: Vec<(String, usize)>,
+}
+
+fn entry(item: &Item, binaries: &mut Vec<Zeroizing<Vec<u8>>>) -> Result<Entry> {
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+
+    let mut strings = Vec::new();
+    for key in &["Title", "UserName", "Password", "URL", "Notes"] {
+        add_string(&mut strings, key, String::new(), *key == "Password");
+    }
+    set_string(&mut strings, "Title", overview.title.clone().unwrap_or_default());
+    set_string(&mut strings, "Notes", detail.notes().unwrap_or("").to_string());
+
+    let mut urls = overview.url.iter().chain(overview.urls.iter().map(|u| &u.u)).collect::<Vec<_>>();
+    urls.dedup();
+    for (i, url) in urls.into_iter().enumerate() {
+        // KeePassXC picks up additional URLs under these keys
+        match i {
+            0 => set_string(&mut strings, "URL", url.clone()),
+            1 => add_string(&mut strings, "KP2A_URL", url.clone(), false),
+            _ => add_string(&mut strings, &format!("KP2A_URL_{}", i - 1), url.clone(), false),
+        }
+    }
+
+    match *detail {
+        Detail::Login(ref login) => {
+            for field in &login.fields {
+                match field.designation.as_deref() {
+                    Some("username") => set_string(&mut strings, "UserName", field.value.clone()),
+                    Some("password") => set_string(&mut strings, "Password", field.value.clone()),
+                    _ if field.value.is_empty() || field.kind == LoginFieldKind::Button => (),
+                    _ => add_string(&mut strings, &field.name, field.value.clone(), field.kind == LoginFieldKind::Password),
+                }
+            }
+        }
+        Detail::Password(ref password) => set_string(&mut strings, "Password", password.password.clone()),
+        Detail::Generic(_) => (),
+    }
+
+    for section in detail.sections() {
+        for field in &section.fields {
+            let value = match field.value {
+                Some(ref value) => value.to_string(),
+                None => continue,
+            };
+            let key = field.title.as_deref().unwrap_or(&field.name);
+            let key = if key.is_empty() { section.title.as_str() } else { key };
+            add_string(&mut strings, key, value, field.kind == FieldKind::Concealed);
+        }
+    }
+
+    let mut names = Vec::new();
+    for att in item.get_attachments()? {
+        let mut content = Zeroizing::new(Vec::new());
+        att.content_reader()?.read_to_end(&mut content)?;
+        let mut name = att.file_name()?;
+        if names.iter().any(|(n, _)| *n == name) {
+            name = format!("{} ({})", name, names.len() + 1);
+        }
+        names.push((name, binaries.len()));
+        binaries.push(content);
+    }
+
+    Ok(Entry {
+        uuid: item.uuid,
+        category: item.category,
+        created: item.created,
+        updated: item.updated,
+        tags: overview.tags,
+        strings,
+        binaries: names,
+    })
+}
+
+/// The icon and the name of the template for each category
+fn category_style(category: Category) -> (u32, &'static str) {
+    match category {
+        Category::Login => (1, "Login"),
+        Category::CreditCard => (66, "Credit Card"),
+        Category::SecureNote => (44, "Secure Note"),
+        Category::Identity => (9, "Identity"),
+        Category::Password => (0, "Password"),
+        Category::Tombstone => (43, "Tombstone"),
+        Category::SoftwareLicense => (67, "Software License"),
+        Category::BankAccount => (37, "Bank Account"),
+        Category::Database => (27, "Database"),
+        Category::DriverLicense => (9, "Driver License"),
+        Category::OutdoorLicense => (67, "Outdoor License"),
+        Category::Membership => (58, "Membership"),
+        Category::Passport => (9, "Passport"),
+        Category::Rewards => (61, "Reward Program"),
+        Category::SSN => (9, "Social Security Number"),
+        Category::Router => (8, "Wireless Router"),
+        Category::Server => (3, "Server"),
+        Category::Email => (19, "Email Account"),
+    }
+}
+
+fn document(vault: &UnlockedVault, stream: &mut InnerStream, binaries: &mut Vec<Zeroizing<Vec<u8>>>) -> Result<Zeroizing<String>> {
+    let titles = super::folder_titles(vault)?;
+    let mut entries = Vec::new();
+    for item in super::items(vault) {
+        let folder = item.folder.filter(|f| titles.contains_key(f));
+        entries.push((folder, item.is_trashed(), entry(&item, binaries)?));
+    }
+
+    // One template per category, with the fields the category's entries use
+    let mut templates: Vec<Entry> = Vec::new();
+    for (_, _, entry) in &entries {
+        let index = match templates.iter().position(|t| t.category == entry.category) {
+            Some(i) => i,
+            None => {
+                let mut strings = Vec::new();
+                for key in &["Title", "UserName", "Password", "URL", "Notes"] {
+                    add_string(&mut strings, key, String::new(), *key == "Password");
+                }
+                set_string(&mut strings, "Title", category_style(entry.category).1.to_string());
+                templates.push(Entry {
+                    uuid: crypto::random_uuid()?,
+                    category: entry.category,
+                    created: entry.created,
+                    updated: entry.created,
+                    tags: Vec::new(),
+                    strings,
+                    binaries: Vec::new(),
+                });
+                templates.len() - 1
+            }
+        };
+        let template = &mut templates[index];
+        for field in entry.strings.iter().skip(5) {
+            if !template.strings.iter().any(|f| f.key == field.key) {
+                template.strings.push(StringField { key: field.key.clone(), value: Zeroizing::new(String::new()), protected: field.protected });
+            }
+        }
+    }
+
+    let root = crypto::random_uuid()?;
+    let templates_group = crypto::random_uuid()?;
+    let recycle_bin = crypto::random_uuid()?;
+    let now = super::super::item::timestamp();
+
+    // The document is written twice, first to find out how long it is so
+    // the string holding it is never reallocated. The protected values are
+    // the same length whatever the keystream, so the count uses a copy.
+    let write = |xml: &mut dyn FmtWrite, stream: &mut InnerStream| -> Result<()> {
+        let _ = xml.write_str("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n<KeePassFile>\n<Meta>\n");
+        tag(xml, "Generator", "opvault");
+        tag(xml, "DatabaseName", &vault.profile.profile_name);
+        tag(xml, "DatabaseNameChanged", &time(now));
+        let _ = xml.write_str("<MemoryProtection>\n");
+        for (key, on) in &[("ProtectTitle", false), ("ProtectUserName", false), ("ProtectPassword", true), ("ProtectURL", false), ("ProtectNotes", false)] {
+            tag(xml, key, if *on { "True" } else { "False" });
+        }
+        let _ = xml.write_str("</MemoryProtection>\n");
+        tag(xml, "RecycleBinEnabled", "True");
+        tag(xml, "RecycleBinUUID", &uuid(&recycle_bin));
+        tag(xml, "RecycleBinChanged", &time(now));
+        tag(xml, "EntryTemplatesGroup", &uuid(&templates_group));
+        tag(xml, "EntryTemplatesGroupChanged", &time(now));
+        let _ = xml.write_str("</Meta>\n<Root>\n");
+
+        open_group(xml, &root, &vault.profile.profile_name, 48, now, now);
+        for (folder, trashed, entry) in &entries {
+            if !trashed && folder.is_none() {
+                write_entry(xml, entry, stream)?;
+            }
+        }
+
+        let mut folders: Vec<_> = vault.folders.values().filter(|f| titles.contains_key(&f.uuid)).collect();
+        folders.sort_by(|a, b| titles[&a.uuid].cmp(&titles[&b.uuid]));
+        for folder in folders {
+            open_group(xml, &folder.uuid, &titles[&folder.uuid], 48, folder.created, folder.updated);
+            for (f, trashed, entry) in &entries {
+                if !trashed && *f == Some(folder.uuid) {
+                    write_entry(xml, entry, stream)?;
+                }
+            }
+            let _ = xml.write_str("</Group>\n");
+        }
+
+        open_group(xml, &templates_group, "Templates", 48, now, now);
+        for template in &templates {
+            write_entry(xml, template, stream)?;
+        }
+        let _ = xml.write_str("</Group>\n");
+
+        if entries.iter().any(|(_, trashed, _)| *trashed) {
+            open_group(xml, &recycle_bin, "Recycle Bin", 43, now, now);
+            for (_, trashed, entry) in &entries {
+                if *trashed {
+                    write_entry(xml, entry, stream)?;
+                }
+            }
+            let _ = xml.write_str("</Group>\n");
+        }
+
+        let _ = xml.write_str("</Group>\n<DeletedObjects/>\n</Root>\n</KeePassFile>\n");
+
+        Ok(())
+    };
+
+    let mut len = ByteCount(0);
+    write(&mut len, &mut stream.clone())?;
+    let mut xml = Zeroizing::new(String::with_capacity(len.0));
+    write(&mut *xml, stream)?;
+    debug_assert_eq!(len.0, xml.len());
+
+    Ok(xml)
+}
+
+fn open_group(xml: &mut dyn FmtWrite, id: &Uuid, name: &str, icon: u32, created: i64, updated: i64) {
+    let _ = xml.write_str("<Group>\n");
+    tag(xml, "UUID", &uuid(id));
+    tag(xml, "Name", name);
+    tag(xml, "IconID", &icon.to_string());
+    times(xml, created, updated);
+    tag(xml, "IsExpanded", "True");
+}
+
+fn write_entry(xml: &mut dyn FmtWrite, entry: &Entry, stream: &mut InnerStream) -> Result<()> {
+    let _ = xml.write_str("<Entry>\n");
+    tag(xml, "UUID", &uuid(&entry.uuid));
+    tag(xml, "IconID", &category_style(entry.category).0.to_string());
+    if !entry.tags.is_empty() {
+        tag(xml, "Tags", &entry.tags.join(";"));
+    }
+    times(xml, entry.created, entry.updated);
+    for field in &entry.strings {
+        let _ = xml.write_str("<String>");
+        tag(xml, "Key", &field.key);
+        if field.protected && !field.value.is_empty() {
+            let _ = writeln!(xml, "<Value Protected=\"True\">{}</Value></String>", stream.protect(&field.value)?);
+        } else {
+            tag(xml, "Value", &field.value);
+            let _ = xml.write_str("</String>\n");
+        }
+    }
+    for (name, index) in &entry.binaries {
+        let _ = xml.write_str("<Binary>");
+        tag(xml, "Key", name);
+        let _ = writeln!(xml, "<Value Ref=\"{}\"/></Binary>", index);
+    }
+    let _ = xml.write_str("</Entry>\n");
+
+    Ok(())
+}
+
+fn times(xml: &mut dyn FmtWrite, created: i64, updated: i64) {
+    let _ = xml.write_str("<Times>\n");
+    tag(xml, "CreationTime", &time(created));
+    tag(xml, "LastModificationTime", &time(updated));
+    tag(xml, "LastAccessTime", &time(updated));
+    tag(xml, "ExpiryTime", &time(updated));
+    tag(xml, "Expires", "False");
+    tag(xml, "UsageCount", "0");
+    tag(xml, "LocationChanged", &time(updated));
+    let _ = xml.write_str("</Times>\n");
+}
+
+fn tag(xml: &mut dyn FmtWrite, name: &str, text: &str) {
+    let _ = write!(xml, "<{}>", name);
+    escape(xml, text);
+    let _ = writeln!(xml, "</{}>", name);
+}
+
+/// Escape text for XML, dropping the control characters it can't hold
+fn escape(xml: &mut dyn FmtWrite, text: &str) {
+    for c in text.chars() {
+        let _ = match c {
+            '&' => xml.write_str("&amp;"),
+            '<' => xml.write_str("&lt;"),
+            '>' => xml.write_str("&gt;"),
+            '"' => xml.write_str("&quot;"),
+            '\'' => xml.write_str("&apos;"),
+            '\t' | '\n' | '\r' => xml.write_char(c),
+            c if c < ' ' => Ok(()),
+            c => xml.write_char(c),
+        };
+    }
+}
+
+fn uuid(id: &Uuid) -> String {
+    base64::encode(id.as_bytes())
+}
+
+/// KDBX 4 stores times as base64 seconds since 0001-01-01
+fn time(unix: i64) -> String {
+    base64::encode(&(unix + EPOCH_OFFSET).to_le_bytes())
+}
@@ -0,0 +1,60 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writing the contents of a vault in formats which other password managers
+//! import. Everything is written out decrypted, so the output needs to be
+//! handled as carefully as the master password.
+
+use std::collections::HashMap;
+
+use super::{Result, Item, Category, Uuid, UnlockedVault};
+
+mod onepif;
+#[cfg(feature = "export")]
+mod kdbx;
+#[cfg(feature = "export")]
+mod bitwarden;
+mod csv;
+#[cfg(feature = "1pux")]
+mod onepux;
+
+pub use self::onepif::{write_1pif, SEPARATOR};
+#[cfg(feature = "export")]
+pub use self::kdbx::write_kdbx;
+#[cfg(feature = "export")]
+pub use self::bitwarden::write_bitwarden;
+pub use self::csv::{write_csv, CsvLayout, CsvColumn};
+#[cfg(feature = "1pux")]
+pub use self::onepux::write_1pux;
+
+/// Add PKCS#7 padding, as the crypto backends only deal in whole blocks
+#[cfg(feature = "export")]
+fn pad(data: &mut Vec<u8>) {
+    let n = 16 - data.len() % 16;
+    data.extend(std::iter::repeat_n(n as u8, n));
+}
+
+/// The titles of the regular folders, by UUID
+pub fn folder_titles(vault: &UnlockedVault) -> Result<HashMap<Uuid, String>> {
+    let mut titles = HashMap::new();
+    for (uuid, folder) in vault.folders.iter().filter(|(_, f)| !f.smart) {
+        titles.insert(*uuid, folder.overview()?.title);
+    }
+
+    Ok(titles)
+}
+
+/// The items worth exporting, oldest first so the output is stable.
+/// Tombstones of purged items are left out.
+fn items(vault: &UnlockedVault) -> Vec<Item<'_>> {
+    let mut items: Vec<Item> = vault.get_items()
+        .filter(|item| item.category != Category::Tombstone)
+        .collect();
+    items.sort_by_key(|item| (item.created, item.uuid));
+
+    items
+}
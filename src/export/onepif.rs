@@ -0,0 +1,88 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::io::Write;
+
+use serde_json::{self, Value};
+
+use super::super::{Result, Item, UnlockedVault};
+use super::super::item::uuid_string;
+
+/// The line between the entries of a 1PIF file
+pub const SEPARATOR: &str = "***5642bee8-a5ff-11dc-8314-0800200c9a66***";
+
+/// Write the vault's folders and items in the 1Password Interchange Format.
+/// 1PIF has no room for attachments, so they are left out, as are smart
+/// folders.
+pub fn write_1pif<W: Write>(vault: &UnlockedVault, mut out: W) -> Result<()> {
+    let titles = super::folder_titles(vault)?;
+    let mut folders: Vec<_> = vault.folders.values().filter(|f| titles.contains_key(&f.uuid)).collect();
+    folders.sort_by_key(|f| (f.created, f.uuid));
+    for folder in folders {
+        let entry = json!({
+            "uuid": uuid_string(&folder.uuid),
+            "typeName": "system.folder.Regular",
+            "title": titles[&folder.uuid],
+            "createdAt": folder.created,
+            "updatedAt": folder.updated,
+        });
+        write_entry(&mut out, &entry)?;
+    }
+
+    for item in super::items(vault) {
+        write_entry(&mut out, &item_entry(&item)?)?;
+    }
+
+    Ok(())
+}
+
+fn write_entry<W: Write>(out: &mut W, entry: &Value) -> Result<()> {
+    serde_json::to_writer(&mut *out, entry)?;
+    write!(out, "\n{}\n", SEPARATOR)?;
+
+    Ok(())
+}
+
+fn item_entry(item: &Item) -> Result<Value> {
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+
+    // The details are what 1PIF calls the secure contents, except that the
+    // URLs go in there too.
+    let mut secure = serde_json::to_value(&*detail)?;
+    if !overview.urls.is_empty() {
+        let urls: Vec<Value> = overview.urls.iter().map(|u| json!({ "label": "", "url": u.u })).collect();
+        secure["URLs"] = Value::from(urls);
+    }
+
+    let mut entry = json!({
+        "uuid": uuid_string(&item.uuid),
+        "typeName": item.category.type_name(),
+        "title": overview.title.as_deref().unwrap_or(""),
+        "createdAt": item.created,
+        "updatedAt": item.updated,
+        "securityLevel": "SL5",
+        "secureContents": secure,
+    });
+    if let Some(ref url) = overview.url {
+        entry["location"] = Value::from(url.as_str());
+    }
+    if let Some(ref folder) = item.folder {
+        entry["folderUuid"] = Value::from(uuid_string(folder));
+    }
+    if let Some(fave) = item.fave {
+        entry["faveIndex"] = Value::from(fave);
+    }
+    if !overview.tags.is_empty() {
+        entry["openContents"] = json!({ "tags": overview.tags });
+    }
+    if item.is_trashed() {
+        entry["trashed"] = Value::from(true);
+    }
+
+    Ok(entry)
+}
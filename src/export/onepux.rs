@@ -0,0 +1,201 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::io::{self, Seek, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{self, Value};
+use zip::{CompressionMethod, ZipWriter};
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+
+use super::super::{Result, Item, Uuid, UnlockedVault, Detail, Field, FieldKind, FieldValue};
+use super::super::item::uuid_string;
+
+/// Write the vault as a 1Password Unencrypted Export. This is a zip file
+/// holding the items in `export.data` and the attachments under `files/`.
+/// 1PUX has no folders, so an item's folder becomes one of its tags, and
+/// trashed items are marked as archived.
+pub fn write_1pux<W: Write + Seek>(vault: &UnlockedVault, out: W) -> Result<()> {
+    let folders = super::folder_titles(vault)?;
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(out);
+
+    let mut items = Vec::new();
+    for item in super::items(vault) {
+        let mut files = Vec::new();
+        for att in item.get_attachments()? {
+            let name = att.file_name()?;
+            let id = uuid_string(&att.uuid);
+            // The name is up to whoever made the vault, so it mustn't be able
+            // to put the file anywhere else when unzipped
+            zip.start_file(format!("files/{}__{}", id, att.safe_file_name()?), options).map_err(zip_error)?;
+            io::copy(&mut att.content_reader()?, &mut zip)?;
+            files.push(json!({
+                "title": name,
+                "id": id,
+                "value": { "file": { "fileName": name, "documentId": id, "decryptedSize": att.contents_size } },
+            }));
+        }
+        items.push(item_entry(&item, &folders, files)?);
+    }
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let attributes = json!({
+        "version": 3,
+        "description": "1Password Unencrypted Export",
+        "createdAt": created,
+    });
+    let data = json!({
+        "accounts": [{
+            "attrs": { "accountName": "", "name": "", "avatar": "", "email": "", "uuid": "", "domain": "" },
+            "vaults": [{
+                "attrs": {
+                    "uuid": vault.profile.uuid,
+                    "desc": "",
+                    "avatar": "",
+                    "name": vault.profile.profile_name,
+                    "type": "P",
+                },
+                "items": items,
+            }],
+        }],
+    });
+
+    zip.start_file("export.attributes", options).map_err(zip_error)?;
+    serde_json::to_writer(&mut zip, &attributes)?;
+    zip.start_file("export.data", options).map_err(zip_error)?;
+    serde_json::to_writer(&mut zip, &data)?;
+    zip.finish().map_err(zip_error)?;
+
+    Ok(())
+}
+
+fn zip_error(e: ZipError) -> io::Error {
+    io::Error::other(e)
+}
+
+fn item_entry(item: &Item, folders: &HashMap<Uuid, String>, files: Vec<Value>) -> Result<Value> {
+    let overview = item.overview()?;
+    let detail = item.detail()?;
+
+    let mut tags = overview.tags.clone();
+    if let Some(title) = item.folder.as_ref().and_then(|f| folders.get(f)) {
+        if !tags.contains(title) {
+            tags.push(title.clone());
+        }
+    }
+    let urls: Vec<Value> = overview.urls.iter().map(|u| json!({ "label": "", "url": u.u })).collect();
+
+    Ok(json!({
+        "uuid": uuid_string(&item.uuid),
+        "favIndex": item.fave.unwrap_or(0),
+        "createdAt": item.created,
+        "updatedAt": item.updated,
+        "state": if item.is_trashed() { "archived" } else { "active" },
+        "categoryUuid": item.category.code(),
+        "details": details(&detail, files)?,
+        "overview": {
+            "subtitle": overview.ainfo.as_deref().unwrap_or(""),
+            "urls": urls,
+            "title": overview.title.as_deref().unwrap_or(""),
+            "url": overview.url.as_deref().unwrap_or(""),
+            "ps": overview.ps.unwrap_or(0),
+            "pbe": 0.0,
+            "pgrng": false,
+            "tags": tags,
+        },
+    }))
+}
+
+fn details(detail: &Detail, files: Vec<Value>) -> Result<Value> {
+    let mut details = json!({
+        "loginFields": [],
+        "notesPlain": detail.notes().unwrap_or(""),
+        "sections": [],
+        "passwordHistory": [],
+    });
+
+    match *detail {
+        Detail::Login(ref login) => {
+            let mut fields = Vec::new();
+            for field in &login.fields {
+                // The same as in OPVault, except for the name of the type
+                let mut value = serde_json::to_value(field)?;
+                if let Some(kind) = value.as_object_mut().and_then(|o| o.remove("type")) {
+                    value["fieldType"] = kind;
+                }
+                value["id"] = Value::from("");
+                fields.push(value);
+            }
+            details["loginFields"] = Value::from(fields);
+            if let Some(ref form) = login.html_form {
+                details["htmlForm"] = serde_json::to_value(form)?;
+            }
+        }
+        Detail::Password(ref password) => details["password"] = Value::from(password.password.as_str()),
+        Detail::Generic(_) => (),
+    }
+
+    let mut sections: Vec<Value> = detail.sections().iter().map(|section| {
+        let fields: Vec<Value> = section.fields.iter().enumerate().map(|(i, f)| field(f, i)).collect();
+        json!({ "title": section.title, "name": section.name, "fields": fields })
+    }).collect();
+    if !files.is_empty() {
+        sections.push(json!({ "title": "", "name": "attachments", "fields": files }));
+    }
+    details["sections"] = Value::from(sections);
+
+    Ok(details)
+}
+
+/// A section field. 1PUX says what kind of value it is by the key it's
+/// stored under.
+fn field(f: &Field, index: usize) -> Value {
+    let text = f.value.as_ref().map(|v| v.to_string()).unwrap_or_default();
+    let number = match f.value {
+        Some(FieldValue::I64(n)) => n,
+        _ => text.parse().unwrap_or(0),
+    };
+    let value = match f.kind {
+        FieldKind::String => json!({ "string": text }),
+        FieldKind::Concealed => json!({ "concealed": text }),
+        FieldKind::Email => json!({ "email": { "email_address": text, "provider": null } }),
+        FieldKind::URL => json!({ "url": text }),
+        FieldKind::Phone => json!({ "phone": text }),
+        FieldKind::Menu => json!({ "menu": text }),
+        FieldKind::Gender => json!({ "gender": text }),
+        FieldKind::Cctype => json!({ "creditCardType": text }),
+        FieldKind::Date => json!({ "date": number }),
+        FieldKind::MonthYear => json!({ "monthYear": number }),
+        FieldKind::Address => {
+            let address = match f.value {
+                Some(FieldValue::Address(ref a)) => a,
+                _ => return json!({ "title": f.title.as_deref().unwrap_or(""), "id": f.name, "value": { "string": text } }),
+            };
+            json!({ "address": {
+                "street": address.street.as_deref().unwrap_or(""),
+                "city": address.city.as_deref().unwrap_or(""),
+                "country": address.country.as_deref().unwrap_or(""),
+                "zip": address.zip.as_deref().unwrap_or(""),
+                "state": address.state.as_deref().unwrap_or(""),
+            } })
+        }
+    };
+    let attr = f.attr.as_ref();
+
+    json!({
+        "title": f.title.as_deref().unwrap_or(""),
+        "id": f.name,
+        "value": value,
+        "indexAtSource": index,
+        "guarded": attr.and_then(|a| a.guarded.as_deref()) == Some("yes"),
+        "multiline": false,
+        "dontGenerate": attr.and_then(|a| a.generate.as_deref()) == Some("off"),
+    })
+}
@@ -11,29 +11,32 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 
-use serde::de;
+use serde::{de, ser};
 use serde::Deserialize;
 use serde_json;
+use serde_json::{Map, Value};
 use base64;
-use super::opdata01;
+use super::{opdata01, atomic, crypto};
+use super::item::{uuid_string, uuid_ser, timestamp};
 use super::{Result, OverviewKey, Uuid};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct FolderData {
     pub created: i64,
-    #[serde(deserialize_with = "base64_deser")]
+    #[serde(deserialize_with = "base64_deser", serialize_with = "base64_ser")]
     pub overview: Vec<u8>,
     pub tx: i64,
     pub updated: i64,
+    #[serde(serialize_with = "uuid_ser")]
     pub uuid: Uuid,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub smart: bool,
 }
 
 /// A "folder" or named group of items.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Folder {
     pub created: i64,
     pub tx: i64,
@@ -41,11 +44,11 @@ pub struct Folder {
     pub uuid: Uuid,
     pub smart: bool,
     overview: Vec<u8>,
-    overview_key: Rc<OverviewKey>
+    overview_key: Arc<OverviewKey>
 }
 
 impl Folder {
-    fn from_folder_data(d: FolderData, overview_key: Rc<OverviewKey>) -> Result<Folder> {
+    fn from_folder_data(d: FolderData, overview_key: Arc<OverviewKey>) -> Result<Folder> {
         Ok(Folder {
             created: d.created,
             overview: d.overview,
@@ -57,6 +60,17 @@ impl Folder {
         })
     }
 
+    fn to_folder_data(&self) -> FolderData {
+        FolderData {
+            created: self.created,
+            overview: self.overview.clone(),
+            tx: self.tx,
+            updated: self.updated,
+            uuid: self.uuid,
+            smart: self.smart,
+        }
+    }
+
     /// Decrypt the folder's overview data
     pub fn overview(&self) -> Result<Overview> {
         let key = self.overview_key.clone();
@@ -68,8 +82,43 @@ impl Folder {
     }
 }
 
+/// Create a new folder with the given title
+pub fn new_folder(title: &str, overview_key: Arc<OverviewKey>) -> Result<Folder> {
+    let mut overview = Map::new();
+    overview.insert("title".to_string(), Value::String(title.to_string()));
+    let raw = serde_json::to_vec(&overview)?;
+    let now = timestamp();
+
+    Ok(Folder {
+        created: now,
+        tx: now,
+        updated: now,
+        uuid: crypto::random_uuid()?,
+        smart: false,
+        overview: opdata01::encrypt(&raw, overview_key.encryption(), overview_key.verification())?,
+        overview_key,
+    })
+}
+
+/// Change a folder's title. Anything else in the overview, such as the
+/// predicate of a smart folder, is kept.
+pub fn rename(folder: &mut Folder, title: &str) -> Result<()> {
+    let key = folder.overview_key.clone();
+    let raw = opdata01::decrypt(&folder.overview[..], key.encryption(), key.verification())?;
+    let mut overview: Map<String, Value> = serde_json::from_slice(&raw)?;
+    overview.insert("title".to_string(), Value::String(title.to_string()));
+
+    let raw = serde_json::to_vec(&overview)?;
+    folder.overview = opdata01::encrypt(&raw, key.encryption(), key.verification())?;
+    let now = timestamp();
+    folder.updated = now;
+    folder.tx = now;
+
+    Ok(())
+}
+
 /// Read the encrypted folder data
-pub fn read_folders(p: &Path, overview_key: Rc<OverviewKey>) -> Result<HashMap<Uuid, Folder>> {
+pub fn read_folders(p: &Path, overview_key: Arc<OverviewKey>) -> Result<HashMap<Uuid, Folder>> {
     let mut f = match File::open(p) {
         Ok(x) => x,
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
@@ -91,6 +140,51 @@ pub fn read_folders(p: &Path, overview_key: Rc<OverviewKey>) -> Result<HashMap<U
     Ok(folders)
 }
 
+/// Read the folder entries without interpreting them so we can write back the
+/// fields we don't know about.
+fn read_raw_folders(p: &Path) -> Result<Map<String, Value>> {
+    let mut f = match File::open(p) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Map::new()),
+        Err(e) => return Err(From::from(e)),
+    };
+
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+    let json_str = s.trim_start_matches("loadFolders(").trim_end_matches(");");
+
+    Ok(serde_json::from_str(json_str)?)
+}
+
+fn write_raw_folders(p: &Path, folders: &Map<String, Value>) -> Result<()> {
+    let contents = format!("loadFolders({});", serde_json::to_string_pretty(folders)?);
+    atomic::write(p, contents.as_bytes())
+}
+
+/// Write a folder into the folders file, replacing any previous version of it
+pub fn write_folder(p: &Path, folder: &Folder) -> Result<()> {
+    let mut folders = read_raw_folders(p)?;
+    let id = uuid_string(&folder.uuid);
+    let mut entry = match folders.remove(&id) {
+        Some(Value::Object(m)) => m,
+        _ => Map::new(),
+    };
+    if let Value::Object(m) = serde_json::to_value(folder.to_folder_data())? {
+        entry.extend(m);
+    }
+    folders.insert(id, Value::Object(entry));
+
+    write_raw_folders(p, &folders)
+}
+
+/// Remove a folder from the folders file
+pub fn remove_folder(p: &Path, uuid: &Uuid) -> Result<()> {
+    let mut folders = read_raw_folders(p)?;
+    folders.remove(&uuid_string(uuid));
+
+    write_raw_folders(p, &folders)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Overview {
     pub title: String,
@@ -110,6 +204,16 @@ fn base64_deser<'de, D> (d: D) -> std::result::Result<Vec<u8>, D::Error>
     }
 }
 
+fn base64_ser<S>(d: &[u8], s: S) -> std::result::Result<S::Ok, S::Error>
+    where S: ser::Serializer
+{
+    s.serialize_str(&base64::encode(d))
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
 impl Overview {
     pub fn from_slice(d: &[u8]) -> serde_json::Result<Overview> {
         serde_json::from_slice(d)
@@ -0,0 +1,38 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use super::{Result, Zeroizing};
+use super::crypto::random_bytes;
+
+static ALPHANUMERIC: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+static SYMBOLS: &[u8] = b"!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Generate a random password of the given length from letters, digits and,
+/// optionally, symbols.
+pub fn generate_password(length: usize, symbols: bool) -> Result<Zeroizing<String>> {
+    let mut charset = ALPHANUMERIC.to_vec();
+    if symbols {
+        charset.extend_from_slice(SYMBOLS);
+    }
+    // Bytes past the last whole multiple of the charset's size would make the
+    // first characters more likely, so we throw those away.
+    let limit = 256 - 256 % charset.len();
+
+    let mut password = Zeroizing::new(String::with_capacity(length));
+    let mut buf = Zeroizing::new([0u8; 64]);
+    while password.len() < length {
+        random_bytes(&mut buf[..])?;
+        for b in buf.iter().map(|b| usize::from(*b)).filter(|b| *b < limit) {
+            if password.len() == length {
+                break;
+            }
+            password.push(char::from(charset[b % charset.len()]));
+        }
+    }
+
+    Ok(password)
+}
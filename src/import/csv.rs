@@ -0,0 +1,105 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::str::FromStr;
+
+use super::super::{Result, Error, Category, CsvColumn, CsvLayout, Detail, FieldKind, Overview, URL, UnlockedVault, Uuid, Zeroizing};
+use super::super::crypto;
+use super::super::csv::{read_records, unescape_formula};
+use super::NewItem;
+
+/// Add the rows of a CSV file to the vault as items. The first row holds the
+/// headers. With a layout, columns are picked by the headers it gives them,
+/// and columns it doesn't know are skipped; without one, the headers name
+/// the columns as in `CsvLayout::parse`. A `category` column holds category
+/// codes such as `001` or 1PIF type names. Without one, rows with a
+/// username, password or URL become logins and the others secure notes.
+/// Folders are found by title, and created if they're missing, and tags are
+/// separated by commas. A `'` which the layout's `escape_formulas` put in
+/// front of a value is taken out again. Nothing is added unless every row
+/// can be read. Returns the UUIDs of the new items.
+pub fn import_csv(vault: &mut UnlockedVault, layout: Option<&CsvLayout>, input: &str) -> Result<Vec<Uuid>> {
+    let records = Zeroizing::new(read_records(input)?);
+    let (headers, rows) = match records.split_first() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let columns: Vec<Option<CsvColumn>> = headers.iter()
+        .map(|header| match layout {
+            Some(layout) => layout.columns.iter().find(|(h, _)| h.eq_ignore_ascii_case(header.trim())).map(|(_, c)| c.clone()),
+            None => header.parse().ok(),
+        })
+        .collect();
+
+    // Without a layout, the columns are as in one from `CsvLayout::parse`
+    let escaped = layout.is_none_or(|layout| layout.escape_formulas);
+
+    let mut items = Vec::new();
+    for row in rows {
+        let values: Vec<(&CsvColumn, &str)> = columns.iter().zip(row.iter())
+            .filter_map(|(column, value)| column.as_ref().map(|c| (c, value.as_str())))
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(column, value)| (column, if escaped { unescape_formula(value) } else { value }))
+            .collect();
+
+        let category = match values.iter().find(|(c, _)| **c == CsvColumn::Category) {
+            Some((_, code)) => match Category::from_type_name(code) {
+                Some(category) => category,
+                None => Category::from_str(code)?,
+            },
+            None if values.iter().any(|(c, _)| [CsvColumn::Username, CsvColumn::Password, CsvColumn::Url].contains(c)) => Category::Login,
+            None => Category::SecureNote,
+        };
+        if category == Category::Tombstone {
+            return Err(Error::ItemError);
+        }
+
+        let mut overview = Overview::default();
+        let mut detail = Zeroizing::new(Detail::new(category));
+        let mut folder = None;
+        for (column, value) in values {
+            let value = value.to_string();
+            match *column {
+                CsvColumn::Title => overview.title = Some(value),
+                CsvColumn::Url => {
+                    overview.urls.push(URL { u: value.clone() });
+                    overview.url.get_or_insert(value);
+                }
+                CsvColumn::Notes => detail.set_notes(Some(value)),
+                CsvColumn::Folder => folder = Some(value),
+                CsvColumn::Tags => {
+                    overview.tags.extend(value.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()));
+                }
+                CsvColumn::Username => {
+                    match *detail {
+                        Detail::Login(ref mut login) => login.set_designated("username", value.clone()),
+                        _ => detail.set_field("username", FieldKind::String, value.clone()),
+                    }
+                    overview.ainfo = Some(value);
+                }
+                CsvColumn::Password => match *detail {
+                    Detail::Login(ref mut login) => login.set_designated("password", value),
+                    Detail::Password(ref mut p) => p.password = value,
+                    Detail::Generic(_) => detail.set_field("password", FieldKind::Concealed, value),
+                },
+                // 1Password finds one-time password secrets by this prefix
+                CsvColumn::Totp => {
+                    let name = format!("TOTP_{}", crypto::random_uuid()?.to_simple_ref()).to_uppercase();
+                    detail.set_field(&name, FieldKind::Concealed, value);
+                }
+                CsvColumn::Field(ref path) => detail.set_field(path, FieldKind::String, value),
+                // The vault keeps favourites in an order of their own, which
+                // a CSV file can't give.
+                CsvColumn::Favorite | CsvColumn::Category => (),
+            }
+        }
+
+        items.push(NewItem { category, overview, detail, folder, trashed: false });
+    }
+
+    super::add_items(vault, &[], items)
+}
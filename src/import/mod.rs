@@ -0,0 +1,78 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reading items which other password managers exported into a vault. The
+//! importers take the formats the `export` module writes, so a vault can be
+//! copied into another one, as well as files from other sources.
+
+use std::collections::HashMap;
+
+use super::{Result, Category, Detail, Overview, Uuid, UnlockedVault, Zeroizing};
+use super::export::folder_titles;
+
+mod onepif;
+mod csv;
+
+pub use self::onepif::import_1pif;
+pub use self::csv::import_csv;
+
+/// The vault's regular folders by title, so items land in an existing folder
+/// of the same name rather than a copy of it
+struct Folders(HashMap<String, Uuid>);
+
+impl Folders {
+    fn new(vault: &UnlockedVault) -> Result<Folders> {
+        let titles = folder_titles(vault)?;
+        Ok(Folders(titles.into_iter().map(|(uuid, title)| (title.to_lowercase(), uuid)).collect()))
+    }
+
+    /// The folder with this title, created if there isn't one
+    fn get(&mut self, vault: &mut UnlockedVault, title: &str) -> Result<Uuid> {
+        if let Some(uuid) = self.0.get(&title.to_lowercase()) {
+            return Ok(*uuid);
+        }
+
+        let uuid = vault.create_folder(title)?;
+        self.0.insert(title.to_lowercase(), uuid);
+        Ok(uuid)
+    }
+}
+
+/// An item read from a file. The whole file is read before any of them are
+/// written, so one which can't be read doesn't leave the others half-imported.
+struct NewItem {
+    category: Category,
+    overview: Overview,
+    detail: Zeroizing<Detail>,
+    /// The title of the folder it goes in
+    folder: Option<String>,
+    trashed: bool,
+}
+
+/// Store the new items, creating the folders they go in, as well as the
+/// other folders given, where the vault has none with the same title
+fn add_items(vault: &mut UnlockedVault, folder_titles: &[String], items: Vec<NewItem>) -> Result<Vec<Uuid>> {
+    let mut folders = Folders::new(vault)?;
+    for title in folder_titles {
+        folders.get(vault, title)?;
+    }
+
+    let mut added = Vec::new();
+    for item in items {
+        let uuid = vault.add_item(item.category, &item.overview, &item.detail)?;
+        if let Some(ref title) = item.folder {
+            let folder = folders.get(vault, title)?;
+            vault.move_to_folder(&uuid, Some(&folder))?;
+        }
+        if item.trashed {
+            vault.trash_item(&uuid)?;
+        }
+        added.push(uuid);
+    }
+
+    Ok(added)
+}
@@ -0,0 +1,105 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use super::super::{Result, Error, Category, Detail, Overview, URL, UnlockedVault, Uuid, Zeroizing};
+use super::super::export::SEPARATOR;
+use super::super::detail::zeroize_value;
+use super::NewItem;
+
+/// Add the items in a 1Password Interchange Format file to the vault, along
+/// with their folders and tags. Folders are matched to the vault's own by
+/// title, and only created if they're missing. Items keep their category and
+/// fields, and trashed items go into the trash. Tombstones, smart folders and
+/// entries of unknown kinds which aren't items are skipped, and items of
+/// unknown categories become secure notes. Nothing is added unless every
+/// entry can be read. Returns the UUIDs of the new items.
+///
+/// The parsed entries are wiped once the items are read from them, but
+/// serde_json may leave copies of the secrets behind while parsing.
+pub fn import_1pif(vault: &mut UnlockedVault, input: &str) -> Result<Vec<Uuid>> {
+    let mut entries = Vec::new();
+    for entry in input.split(SEPARATOR) {
+        let entry = entry.trim();
+        if !entry.is_empty() {
+            entries.push(Entry(serde_json::from_str(entry)?));
+        }
+    }
+
+    // Items may come before the folders they're in
+    let mut folders = HashMap::new();
+    for entry in entries.iter().filter(|e| e.0["typeName"] == "system.folder.Regular") {
+        let uuid = entry.0["uuid"].as_str().ok_or(Error::FolderError)?;
+        let title = entry.0["title"].as_str().unwrap_or("");
+        folders.insert(uuid.to_string(), title.to_string());
+    }
+
+    let mut items = Vec::new();
+    for entry in entries.iter_mut() {
+        let type_name = entry.0["typeName"].as_str().ok_or(Error::ItemError)?;
+        let category = match Category::from_type_name(type_name) {
+            Some(Category::Tombstone) => continue,
+            Some(category) => category,
+            None if type_name.starts_with("system.") => continue,
+            None => Category::SecureNote,
+        };
+        items.push(read_item(category, entry, &folders)?);
+    }
+    drop(entries);
+
+    let mut titles: Vec<String> = folders.into_values().collect();
+    titles.sort();
+    super::add_items(vault, &titles, items)
+}
+
+/// An entry of the file, whose secrets are wiped when it's dropped
+struct Entry(Value);
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        zeroize_value(&mut self.0);
+    }
+}
+
+fn read_item(category: Category, entry: &mut Entry, folders: &HashMap<String, String>) -> Result<NewItem> {
+    // The URLs are kept with the secure contents in 1PIF, but in the
+    // overview in the vault.
+    let mut secure = Entry(entry.0["secureContents"].take());
+    let urls = match secure.0.as_object_mut().and_then(|s| s.remove("URLs")) {
+        Some(Value::Array(urls)) => urls,
+        _ => Vec::new(),
+    };
+    let entry = &entry.0;
+
+    let mut overview = Overview {
+        title: entry["title"].as_str().map(|s| s.to_string()),
+        url: entry["location"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string()),
+        urls: urls.iter().filter_map(|u| u["url"].as_str()).map(|u| URL { u: u.to_string() }).collect(),
+        ..Overview::default()
+    };
+    if let Some(tags) = entry["openContents"]["tags"].as_array() {
+        overview.tags = tags.iter().filter_map(|t| t.as_str()).map(|t| t.to_string()).collect();
+    }
+
+    let detail = if secure.0.is_null() {
+        Zeroizing::new(Detail::new(category))
+    } else {
+        Zeroizing::new(Detail::from_slice(category, &Zeroizing::new(serde_json::to_vec(&secure.0)?))?)
+    };
+    overview.ainfo = detail.username().map(|s| s.to_string());
+
+    Ok(NewItem {
+        category,
+        overview,
+        detail,
+        folder: entry["folderUuid"].as_str().and_then(|f| folders.get(f)).cloned(),
+        trashed: entry["trashed"] == true,
+    })
+}
@@ -0,0 +1,196 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Items, attachments and folders which fail verification are left out of
+//! the vault. These types describe what was left out and why, along with the
+//! inconsistencies between files that `UnlockedVault::check` looks for.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use super::{Error, Uuid};
+use super::item::uuid_string;
+
+/// An item which is left out of the vault
+#[derive(Debug)]
+pub struct RejectedItem {
+    /// The band file the item is stored in
+    pub file: PathBuf,
+    /// The UUID the item is stored under. This is what's in the file, so it
+    /// need not be a valid UUID.
+    pub uuid: String,
+    pub error: Error,
+}
+
+/// A band file which can't be parsed in full. The items before the damage are
+/// still read.
+#[derive(Debug)]
+pub struct DamagedBand {
+    pub file: PathBuf,
+    pub error: Error,
+}
+
+/// An attachment which can't be read or fails verification
+#[derive(Debug)]
+pub struct BadAttachment {
+    pub file: PathBuf,
+    /// The attachment's UUID, if we got as far as reading its metadata
+    pub uuid: Option<Uuid>,
+    pub error: Error,
+}
+
+/// A folder whose overview can't be decrypted
+#[derive(Debug)]
+pub struct BadFolder {
+    pub uuid: Uuid,
+    pub error: Error,
+}
+
+/// Everything in the vault which failed verification
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub damaged_bands: Vec<DamagedBand>,
+    pub rejected_items: Vec<RejectedItem>,
+    pub bad_attachments: Vec<BadAttachment>,
+    pub bad_folders: Vec<BadFolder>,
+}
+
+impl IntegrityReport {
+    /// Whether everything in the vault verified
+    pub fn is_clean(&self) -> bool {
+        self.damaged_bands.is_empty() && self.rejected_items.is_empty() &&
+            self.bad_attachments.is_empty() && self.bad_folders.is_empty()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for band in &self.damaged_bands {
+            writeln!(f, "{} is damaged: {}", band.file.display(), band.error)?;
+        }
+        for item in &self.rejected_items {
+            writeln!(f, "item {} in {}: {}", item.uuid, item.file.display(), item.error)?;
+        }
+        for att in &self.bad_attachments {
+            writeln!(f, "attachment {}: {}", att.file.display(), att.error)?;
+        }
+        for folder in &self.bad_folders {
+            writeln!(f, "folder {}: {}", uuid_string(&folder.uuid), folder.error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An inconsistency between the files in the vault
+#[derive(Debug)]
+pub enum Problem {
+    /// An attachment belongs to an item which is not in the vault
+    OrphanAttachment { file: PathBuf, uuid: Uuid, item: Uuid },
+    /// An item is in a folder which is not in the vault
+    UnknownFolder { item: Uuid, folder: Uuid },
+    /// An item is stored more than once, in these band files
+    DuplicateItem { uuid: Uuid, files: Vec<PathBuf> },
+    /// An item is not in the band file its UUID belongs in
+    MisplacedItem { uuid: Uuid, file: PathBuf, expected: PathBuf },
+    /// The sizes in an attachment's header don't add up
+    BadAttachmentLayout { file: PathBuf, reason: &'static str },
+    /// An item verifies but its overview or details can't be decrypted or
+    /// parsed
+    UnreadableItem { uuid: Uuid, error: Error },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Problem::OrphanAttachment { ref file, ref item, .. } => {
+                write!(f, "attachment {} belongs to unknown item {}", file.display(), uuid_string(item))
+            }
+            Problem::UnknownFolder { ref item, ref folder } => {
+                write!(f, "item {} is in unknown folder {}", uuid_string(item), uuid_string(folder))
+            }
+            Problem::DuplicateItem { ref uuid, ref files } => {
+                write!(f, "item {} is stored {} times in", uuid_string(uuid), files.len())?;
+                for file in files {
+                    write!(f, " {}", file.display())?;
+                }
+                Ok(())
+            }
+            Problem::MisplacedItem { ref uuid, ref file, ref expected } => {
+                write!(f, "item {} is in {} instead of {}", uuid_string(uuid), file.display(), expected.display())
+            }
+            Problem::BadAttachmentLayout { ref file, reason } => {
+                write!(f, "attachment {}: {}", file.display(), reason)
+            }
+            Problem::UnreadableItem { ref uuid, ref error } => {
+                write!(f, "item {} can't be read: {}", uuid_string(uuid), error)
+            }
+        }
+    }
+}
+
+/// The result of checking the whole vault
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// What failed verification
+    pub integrity: IntegrityReport,
+    /// What's inconsistent between the files
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Whether no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.integrity.is_clean() && self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.integrity)?;
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Something `UnlockedVault::repair` did to the vault
+#[derive(Debug)]
+pub enum RepairAction {
+    /// A damaged band file was copied to the quarantine directory and written
+    /// out again with the items which verify
+    RewroteBand { file: PathBuf, backup: PathBuf, kept: usize },
+    /// An item was moved into the band file its UUID belongs in
+    MovedItem { uuid: Uuid, from: PathBuf, to: PathBuf },
+    /// An item in the wrong band file was removed, as the right one already
+    /// had the same or a newer version of it
+    RemovedDuplicate { uuid: Uuid, file: PathBuf },
+    /// An attachment whose item is not in the vault was moved to the
+    /// quarantine directory
+    QuarantinedAttachment { file: PathBuf, to: PathBuf },
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RepairAction::RewroteBand { ref file, ref backup, kept } => {
+                write!(f, "rewrote {} with {} items, the damaged file is in {}", file.display(), kept, backup.display())
+            }
+            RepairAction::MovedItem { ref uuid, ref from, ref to } => {
+                write!(f, "moved item {} from {} to {}", uuid_string(uuid), from.display(), to.display())
+            }
+            RepairAction::RemovedDuplicate { ref uuid, ref file } => {
+                write!(f, "removed old copy of item {} from {}", uuid_string(uuid), file.display())
+            }
+            RepairAction::QuarantinedAttachment { ref file, ref to } => {
+                write!(f, "moved attachment {} to {}", file.display(), to.display())
+            }
+        }
+    }
+}
@@ -6,24 +6,30 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::result;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::collections::hash_map::Values as HashMapValues;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use serde::Serializer;
 use serde_json;
+use serde_json::{Map, Value};
 use base64;
-use super::crypto::{verify_data, decrypt_data, hmac};
-use super::opdata01;
-use super::{Result, Error, MasterKey, OverviewKey, ItemKey, HmacKey, Uuid, AttachmentIterator};
+use super::crypto::{verify_data, verify_mac, decrypt_data, encrypt_data, hmac, random_bytes};
+use super::{opdata01, atomic};
+use super::{Result, Error, Context, Zeroizing, MasterKey, OverviewKey, ItemKey, HmacKey, Uuid, AttachmentIterator};
 use super::attachment::{AttachmentData, Attachment};
 use super::attachment;
 use super::detail::{Detail};
 use super::overview::Overview;
+use super::integrity::{RejectedItem, DamagedBand, RepairAction};
+use super::OpdataError;
 
 /// These are the kinds of items that 1password knows about
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -75,16 +81,76 @@ impl FromStr for Category {
     }
 }
 
-#[derive(Debug, Deserialize)]
+impl Category {
+    /// The code under which this category is stored in the vault
+    pub fn code(self) -> &'static str {
+        match self {
+            Category::Login => "001",
+            Category::CreditCard => "002",
+            Category::SecureNote => "003",
+            Category::Identity => "004",
+            Category::Password => "005",
+            Category::Tombstone => "099",
+            Category::SoftwareLicense => "100",
+            Category::BankAccount => "101",
+            Category::Database => "102",
+            Category::DriverLicense => "103",
+            Category::OutdoorLicense => "104",
+            Category::Membership => "105",
+            Category::Passport => "106",
+            Category::Rewards => "107",
+            Category::SSN => "108",
+            Category::Router => "109",
+            Category::Server => "110",
+            Category::Email => "111",
+        }
+    }
+
+    /// The type name used for this category in 1PIF exports
+    pub fn type_name(self) -> &'static str {
+        TYPE_NAMES.iter().find(|(c, _)| *c == self).map(|(_, name)| *name).unwrap_or("")
+    }
+
+    /// The category with this 1PIF type name
+    pub fn from_type_name(name: &str) -> Option<Category> {
+        TYPE_NAMES.iter().find(|(_, n)| *n == name).map(|(c, _)| *c)
+    }
+}
+
+static TYPE_NAMES: &[(Category, &str)] = &[
+    (Category::Login, "webforms.WebForm"),
+    (Category::CreditCard, "wallet.financial.CreditCard"),
+    (Category::SecureNote, "securenotes.SecureNote"),
+    (Category::Identity, "identities.Identity"),
+    (Category::Password, "passwords.Password"),
+    (Category::Tombstone, "system.Tombstone"),
+    (Category::SoftwareLicense, "wallet.computer.License"),
+    (Category::BankAccount, "wallet.financial.BankAccountUS"),
+    (Category::Database, "wallet.computer.Database"),
+    (Category::DriverLicense, "wallet.government.DriversLicense"),
+    (Category::OutdoorLicense, "wallet.government.HuntingLicense"),
+    (Category::Membership, "wallet.membership.Membership"),
+    (Category::Passport, "wallet.government.Passport"),
+    (Category::Rewards, "wallet.membership.RewardProgram"),
+    (Category::SSN, "wallet.government.SsnUS"),
+    (Category::Router, "wallet.computer.Router"),
+    (Category::Server, "wallet.computer.UnixServer"),
+    (Category::Email, "wallet.onlineservices.Email"),
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ItemData {
     category: String,
     created: i64,
     d: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     fave: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     folder: Option<String>,
     hmac: String,
     k: String,
     o: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     trashed: Option<bool>,
     tx: i64,
     updated: i64,
@@ -107,7 +173,22 @@ macro_rules! update {
 impl ItemData {
     /// Create from the json structure, verifying the integrity of the data given the master hmac key
     fn verify(&self, key: &HmacKey) -> Result<bool> {
-        let actual_hmac = hmac(key, |signer| {
+        let actual_hmac = self.compute_hmac(key)?;
+        let expected_hmac = base64::decode(&self.hmac)?;
+
+        Ok(verify_mac(&actual_hmac, &expected_hmac))
+    }
+
+    /// Calculate the HMAC over the item's fields and store it in the item
+    fn sign(&mut self, key: &HmacKey) -> Result<()> {
+        let actual_hmac = self.compute_hmac(key)?;
+        self.hmac = base64::encode(&actual_hmac);
+
+        Ok(())
+    }
+
+    fn compute_hmac(&self, key: &HmacKey) -> Result<Vec<u8>> {
+        hmac(key, |signer| {
             // This is far from optimal, but we need idents and strings here so any
             // option is bound to lead to some duplication.
             update!(signer, b"category", self.category);
@@ -124,11 +205,7 @@ impl ItemData {
             update!(signer, b"updated", self.updated);
             update!(signer, b"uuid", self.uuid);
             Ok(())
-        })?;
-
-        let expected_hmac = base64::decode(&self.hmac)?;
-
-        Ok(expected_hmac == actual_hmac)
+        })
     }
 }
 
@@ -148,13 +225,14 @@ pub struct Item<'a> {
     pub fave: Option<i64>,
     pub attachments: Vec<Uuid>,
 
+    trashed: bool,
     atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>,
-    master: Rc<MasterKey>,
-    overview: Rc<OverviewKey>,
+    master: Arc<MasterKey>,
+    overview: Arc<OverviewKey>,
 }
 
 impl<'a> Item<'a> {
-    fn from_item_data(d: &ItemData, atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>, master: Rc<MasterKey>, overview: Rc<OverviewKey>) -> Result<Item<'a>> {
+    fn from_item_data(d: &ItemData, atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>, master: Arc<MasterKey>, overview: Arc<OverviewKey>) -> Result<Item<'a>> {
         let uuid = Uuid::parse_str(&d.uuid)?;
         let folder_uuid = if let Some(ref id) = d.folder {
             Some(Uuid::parse_str(id)?)
@@ -180,51 +258,45 @@ impl<'a> Item<'a> {
             uuid,
             fave: d.fave,
             attachments,
+            trashed: d.trashed.unwrap_or(false),
             atts,
             master,
             overview,
         })
     }
 
-    /// Decrypt this item's details
-    pub fn detail(&self) -> Result<Detail> {
-        let keys = self.item_key()?;
-        let raw = opdata01::decrypt(&self.d[..], keys.encryption(), keys.verification())?;
+    /// Whether this item has been moved to the trash
+    pub fn is_trashed(&self) -> bool {
+        self.trashed
+    }
 
-        let res = if self.category == Category::Login {
-            Detail::Login(serde_json::from_slice(&raw)?)
-        } else if self.category == Category::Password {
-            Detail::Password(serde_json::from_slice(&raw)?)
-        } else {
-            Detail::Generic(serde_json::from_slice(&raw)?)
-        };
+    /// Decrypt this item's details. They are wiped from memory when dropped.
+    pub fn detail(&self) -> Result<Zeroizing<Detail>> {
+        let keys = self.item_key()?;
+        let raw = opdata01::decrypt(&self.d[..], keys.encryption(), keys.verification())
+            .in_field("d").in_item(&self.uuid)?;
+        let res = Detail::from_slice(self.category, &raw).in_field("d").in_item(&self.uuid)?;
 
-        Ok(res)
+        Ok(Zeroizing::new(res))
     }
 
     /// Decrypt the item's overview
     pub fn overview(&self) -> Result<Overview> {
-        let raw = opdata01::decrypt(&self.o[..], self.overview.encryption(), self.overview.verification())?;
-        let res = Overview::from_slice(&raw)?;
+        let raw = opdata01::decrypt(&self.o[..], self.overview.encryption(), self.overview.verification())
+            .in_field("o").in_item(&self.uuid)?;
+        let res = Overview::from_slice(&raw).in_field("o").in_item(&self.uuid)?;
 
         Ok(res)
     }
 
     fn item_key(&self) -> Result<ItemKey> {
-        if !verify_data(&self.k[..], self.master.verification())? {
-            return Err(Error::ItemError);
-        }
-
-        let iv = &self.k[..16];
-        let keys = decrypt_data(&self.k[16..], self.master.encryption(), iv)?;
-
-        Ok(keys.into())
+        decrypt_item_key(&self.k[..], &self.master).in_field("k").in_item(&self.uuid)
     }
 
     pub fn get_attachment(&self, id: &Uuid) -> Option<Attachment> {
         if let Ok(key) = self.item_key() {
             if let Some(&(ref data, ref p)) = self.atts.get(id) {
-                return attachment::from_data(data, p.clone(), Rc::new(key), self.overview.clone()).ok()
+                return attachment::from_data(data, p.clone(), Arc::new(key), self.overview.clone()).ok()
             }
         }
 
@@ -236,7 +308,7 @@ impl<'a> Item<'a> {
         Ok(AttachmentIterator {
             inner: self.attachments.iter(),
             atts: self.atts,
-            key: Rc::new(key),
+            key: Arc::new(key),
             overview: self.overview.clone(),
         })
     }
@@ -244,53 +316,419 @@ impl<'a> Item<'a> {
 
 static BANDS: &[u8; 16] = b"0123456789ABCDEF";
 
-// Load the items given the containing path
-pub fn read_items(p: &Path, overview: Rc<OverviewKey>) -> Result<HashMap<Uuid, ItemData>> {
-    let mut map = HashMap::new();
+/// The items in the band files, along with those which fail verification
+/// and the files which are damaged
+pub struct Bands {
+    pub items: HashMap<Uuid, ItemData>,
+    pub rejected: Vec<RejectedItem>,
+    pub damaged: Vec<DamagedBand>,
+}
+
+// Load the items given the containing path. Items which fail verification are
+// returned separately, as are damaged band files. Items are only salvaged from
+// those if asked to.
+pub fn read_items(p: &Path, overview: Arc<OverviewKey>, salvage: bool) -> Result<Bands> {
+    let mut items = HashMap::new();
+    let mut rejected = Vec::new();
+    let mut damaged = Vec::new();
+    for band in read_raw_bands(p, salvage)? {
+        for (uuid, value) in band.entries {
+            match check_item(&uuid, value, &overview) {
+                Ok((id, data)) => {
+                    items.insert(id, data);
+                }
+                Err(error) => rejected.push(RejectedItem { file: band.path.clone(), uuid, error }),
+            }
+        }
+        if let Some(error) = band.damage {
+            damaged.push(DamagedBand { file: band.path, error });
+        }
+    }
+
+    Ok(Bands {
+        items,
+        rejected,
+        damaged,
+    })
+}
+
+/// The entries in a band file, which we haven't interpreted
+pub struct RawBand {
+    pub path: PathBuf,
+    pub entries: Map<String, Value>,
+    /// Why the file couldn't be parsed in full, if it couldn't. The entries are
+    /// then the ones before the damage if they were salvaged, and none if not.
+    pub damage: Option<Error>,
+}
+
+/// Read the entries in all the band files, optionally salvaging what we can
+/// from those which are damaged.
+pub fn read_raw_bands(p: &Path, salvage: bool) -> Result<Vec<RawBand>> {
+    let mut bands = Vec::new();
     for x in BANDS.iter() {
-        let name = format!("band_{}.js", *x as char);
-        let path = p.join(name);
-        let items = read_band(&path, overview.clone())?;
-        map.extend(items);
+        let path = p.join(format!("band_{}.js", *x as char));
+        let s = match read_band_file(&path).in_file(&path)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let json_str = s.trim_start_matches("ld(").trim_end_matches(");");
+        let (entries, damage) = match serde_json::from_str(json_str) {
+            Ok(entries) => (entries, None),
+            Err(e) if salvage => (salvage_band(json_str), Some(Error::from(e))),
+            Err(e) => (Map::new(), Some(Error::from(e))),
+        };
+        bands.push(RawBand { path, entries, damage });
     }
 
-    Ok(map)
+    Ok(bands)
 }
 
-fn read_band(p: &Path, overview: Rc<OverviewKey>) -> Result<HashMap<Uuid, ItemData>> {
+/// Read as many entries as we can from the start of a damaged band file. This
+/// is usually one which was only partly written.
+fn salvage_band(s: &str) -> Map<String, Value> {
+    let mut entries = Map::new();
+    let mut rest = s.trim_start();
+    if !rest.starts_with('{') {
+        return entries;
+    }
+    rest = &rest[1..];
+
+    loop {
+        let mut keys = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+        let key = match keys.next() {
+            Some(Ok(key)) => key,
+            _ => break,
+        };
+        rest = rest[keys.byte_offset()..].trim_start();
+        if !rest.starts_with(':') {
+            break;
+        }
+        rest = &rest[1..];
+
+        let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        let value = match values.next() {
+            Some(Ok(value)) => value,
+            _ => break,
+        };
+        entries.insert(key, value);
+        rest = rest[values.byte_offset()..].trim_start();
+        if !rest.starts_with(',') {
+            break;
+        }
+        rest = &rest[1..];
+    }
+
+    entries
+}
+
+/// Rewrite damaged band files with the items in them which verify, keeping a
+/// copy of the damaged file in the quarantine directory. Then move any items
+/// which verify into the band they belong in. Items which don't verify are
+/// otherwise left where they are.
+pub fn repair_bands(base: &Path, overview: &OverviewKey, quarantine: &Path) -> Result<Vec<RepairAction>> {
+    let mut actions = Vec::new();
+    let mut bands = HashMap::new();
+    for mut band in read_raw_bands(base, true)? {
+        if band.damage.is_some() {
+            let backup = atomic::backup_path(quarantine, &band.path)?;
+            fs::copy(&band.path, &backup).in_file(&backup)?;
+            band.entries.retain(|uuid, value| check_item(uuid, value.clone(), overview).is_ok());
+            write_raw_band(&band.path, &band.entries)?;
+            actions.push(RepairAction::RewroteBand { file: band.path.clone(), backup, kept: band.entries.len() });
+        }
+        bands.insert(band.path, band.entries);
+    }
+
+    let mut misplaced = Vec::new();
+    for (path, entries) in &bands {
+        for (uuid, value) in entries {
+            let to = band_path(base, uuid);
+            if to == *path {
+                continue;
+            }
+            if let Ok((id, data)) = check_item(uuid, value.clone(), overview) {
+                misplaced.push((id, data.tx, uuid.clone(), value.clone(), path.clone(), to));
+            }
+        }
+    }
+
+    for (id, tx, uuid, value, from, to) in misplaced {
+        // The right band may have this item already, or get it from another
+        // wrong one, so we keep the newest.
+        let target = bands.entry(to.clone()).or_default();
+        let newer = target.get(&uuid)
+            .and_then(|existing| check_item(&uuid, existing.clone(), overview).ok())
+            .is_some_and(|(_, existing)| existing.tx >= tx);
+        if !newer {
+            target.insert(uuid.clone(), value);
+            write_raw_band(&to, target)?;
+        }
+
+        // Only take it out of the wrong band once it's safely in the right one
+        let source = bands.entry(from.clone()).or_default();
+        source.remove(&uuid);
+        write_raw_band(&from, source)?;
+        if newer {
+            actions.push(RepairAction::RemovedDuplicate { uuid: id, file: from });
+        } else {
+            actions.push(RepairAction::MovedItem { uuid: id, from, to });
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Read the entries in a band file without interpreting them
+fn read_raw_band(p: &Path) -> Result<Map<String, Value>> {
+    match read_band_file(p)? {
+        Some(s) => Ok(serde_json::from_str(s.trim_start_matches("ld(").trim_end_matches(");"))?),
+        None => Ok(Map::new()),
+    }
+}
+
+/// Write out a band file, wrapped in the same javascript as we found it
+pub fn write_raw_band(p: &Path, band: &Map<String, Value>) -> Result<()> {
+    let contents = format!("ld({});", serde_json::to_string(band)?);
+    atomic::write(p, contents.as_bytes())
+}
+
+/// Parse and verify a band entry
+pub fn check_item(uuid: &str, value: Value, overview: &OverviewKey) -> Result<(Uuid, ItemData)> {
+    let id = Uuid::parse_str(uuid)?;
+    let data: ItemData = serde_json::from_value(value)?;
+    if !data.verify(overview.verification())? {
+        return Err(OpdataError::InvalidHmac).in_field("hmac");
+    }
+
+    Ok((id, data))
+}
+
+fn read_band_file(p: &Path) -> Result<Option<String>> {
     let mut f = match File::open(p) {
-        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
         Err(e) => return Err(From::from(e)),
         Ok(x) => x,
     };
     let mut s = String::new();
     f.read_to_string(&mut s)?;
-    let json_str = s.trim_start_matches("ld(").trim_end_matches(");");
 
-    let mut items: HashMap<Uuid, ItemData> = serde_json::from_str(json_str)?;
-    let valid_items = items.drain()
-        .filter(|&(_, ref i)| i.verify(overview.verification()).ok() == Some(true))
-        .collect();
-    Ok(valid_items)
+    Ok(Some(s))
+}
+
+/// The band file an item belongs in is given by the first digit of its UUID
+pub fn band_path(base: &Path, uuid: &str) -> PathBuf {
+    let band = uuid.chars().next().unwrap_or('0').to_ascii_uppercase();
+    base.join(format!("band_{}.js", band))
 }
 
-pub fn item_from_data<'a>(d: &ItemData, atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>, master: Rc<MasterKey>, overview: Rc<OverviewKey>) -> Result<Item<'a>> {
+/// Write an item into its band file, replacing any previous version of it.
+/// The other entries in the band are kept as they are on disk.
+pub fn write_item(base: &Path, item: &ItemData) -> Result<()> {
+    let path = band_path(base, &item.uuid);
+    let mut band = read_raw_band(&path)?;
+    band.insert(item.uuid.clone(), serde_json::to_value(item)?);
+
+    write_raw_band(&path, &band)
+}
+
+/// The details are parsed according to the category, so we must make sure
+/// we'll be able to read back what we write.
+fn check_detail(category: Category, detail: &Detail) -> Result<()> {
+    match (category, detail) {
+        (Category::Login, &Detail::Login(_)) |
+        (Category::Password, &Detail::Password(_)) => Ok(()),
+        (Category::Login, _) | (Category::Password, _) | (_, &Detail::Login(_)) | (_, &Detail::Password(_)) => {
+            Err(Error::ItemError)
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Encrypt a new item under a freshly generated item key. The overview is
+/// encrypted with the vault's overview key and the details with the item key,
+/// which is itself wrapped with the master key.
+pub fn new_item_data(uuid: &Uuid, category: Category, overview: &Overview, detail: &Detail, master: &MasterKey, overview_key: &OverviewKey) -> Result<ItemData> {
+    check_detail(category, detail)?;
+
+    let mut raw_key = vec![0u8; 64];
+    random_bytes(&mut raw_key)?;
+    let item_key = ItemKey::from(raw_key);
+
+    let o = opdata01::encrypt(&overview.to_vec()?, overview_key.encryption(), overview_key.verification())?;
+    let d = opdata01::encrypt(&detail.to_vec()?, item_key.encryption(), item_key.verification())?;
+    let k = encrypt_item_key(&item_key, master)?;
+    let now = timestamp();
+
+    let mut data = ItemData {
+        category: category.code().to_string(),
+        created: now,
+        d: base64::encode(&d),
+        fave: None,
+        folder: None,
+        hmac: String::new(),
+        k: base64::encode(&k),
+        o: base64::encode(&o),
+        trashed: None,
+        tx: now,
+        updated: now,
+        uuid: uuid_string(uuid),
+    };
+    data.sign(overview_key.verification())?;
+
+    Ok(data)
+}
+
+/// Re-encrypt an item's overview and details under its existing item key,
+/// marking it as updated now.
+pub fn update_item_data(data: &mut ItemData, overview: &Overview, detail: &Detail, master: &MasterKey, overview_key: &OverviewKey) -> Result<()> {
+    check_detail(Category::from_str(&data.category)?, detail)?;
+
+    let item_key = decrypt_item_key(&base64::decode(&data.k)?, master)?;
+    let o = opdata01::encrypt(&overview.to_vec()?, overview_key.encryption(), overview_key.verification())?;
+    let d = opdata01::encrypt(&detail.to_vec()?, item_key.encryption(), item_key.verification())?;
+
+    data.o = base64::encode(&o);
+    data.d = base64::encode(&d);
+    touch(data);
+    data.sign(overview_key.verification())
+}
+
+/// Move an item into or out of the trash
+pub fn set_trashed(data: &mut ItemData, trashed: bool, overview_key: &OverviewKey) -> Result<()> {
+    data.trashed = if trashed { Some(true) } else { None };
+    touch(data);
+    data.sign(overview_key.verification())
+}
+
+/// Move an item into a folder, or out of any folder
+pub fn set_folder(data: &mut ItemData, folder: Option<&Uuid>, overview_key: &OverviewKey) -> Result<()> {
+    data.folder = folder.map(uuid_string);
+    touch(data);
+    data.sign(overview_key.verification())
+}
+
+/// Whether the item is stored in the given folder
+pub fn in_folder(data: &ItemData, folder: &Uuid) -> bool {
+    match data.folder {
+        Some(ref id) => Uuid::parse_str(id).ok().as_ref() == Some(folder),
+        None => false,
+    }
+}
+
+/// Replace an item with a tombstone. This keeps the UUID around so other
+/// clients know the item was deleted, but drops all of its contents.
+pub fn tombstone(data: &mut ItemData, master: &MasterKey, overview_key: &OverviewKey) -> Result<()> {
+    let item_key = decrypt_item_key(&base64::decode(&data.k)?, master)?;
+    let o = opdata01::encrypt(b"{\"ps\":0}", overview_key.encryption(), overview_key.verification())?;
+    let d = opdata01::encrypt(b"{}", item_key.encryption(), item_key.verification())?;
+
+    data.category = Category::Tombstone.code().to_string();
+    data.o = base64::encode(&o);
+    data.d = base64::encode(&d);
+    data.fave = None;
+    data.folder = None;
+    data.trashed = Some(true);
+    touch(data);
+    data.sign(overview_key.verification())
+}
+
+/// Bump the item's modification and transaction timestamps
+fn touch(data: &mut ItemData) {
+    let now = timestamp();
+    data.updated = now;
+    data.tx = now;
+}
+
+/// Retrieve the key for an item's details and attachments
+pub fn item_key(data: &ItemData, master: &MasterKey) -> Result<ItemKey> {
+    decrypt_item_key(&base64::decode(&data.k)?, master)
+}
+
+/// Unwrap an item's key with the master key
+fn decrypt_item_key(k: &[u8], master: &MasterKey) -> Result<ItemKey> {
+    // The IV, the encrypted keys and the HMAC
+    if k.len() != 16 + 64 + 32 {
+        return Err(Error::ItemError);
+    }
+    if !verify_data(k, master.verification())? {
+        return Err(Error::ItemError);
+    }
+
+    let iv = &k[..16];
+    let keys = decrypt_data(&k[16..80], master.encryption(), iv)?;
+
+    Ok(keys.into())
+}
+
+/// Wrap an item's key with the master key. This is the IV followed by the
+/// encrypted key and the HMAC of both.
+fn encrypt_item_key(key: &ItemKey, master: &MasterKey) -> Result<Vec<u8>> {
+    let mut k = vec![0u8; 16];
+    random_bytes(&mut k)?;
+    let encrypted = encrypt_data(key.as_bytes(), master.encryption(), &k)?;
+    k.extend(encrypted);
+
+    let mac = hmac(master.verification(), |signer| signer.update(&k))?;
+    k.extend(mac);
+
+    Ok(k)
+}
+
+/// The UUIDs are stored as upper-case hex without hyphens
+pub fn uuid_string(uuid: &Uuid) -> String {
+    format!("{:X}", uuid.to_simple_ref())
+}
+
+/// Serialize a UUID the way they are stored in the vault
+pub fn uuid_ser<S>(uuid: &Uuid, s: S) -> result::Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    s.serialize_str(&uuid_string(uuid))
+}
+
+/// The current time as stored in the timestamp fields
+pub fn timestamp() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn item_from_data<'a>(d: &ItemData, atts: &'a HashMap<Uuid, (AttachmentData, PathBuf)>, master: Arc<MasterKey>, overview: Arc<OverviewKey>) -> Result<Item<'a>> {
     Item::from_item_data(d, atts, master, overview)
 }
 
 pub struct ItemIterator<'a> {
     pub inner: HashMapValues<'a, Uuid, ItemData>,
-    pub master: Rc<MasterKey>,
-    pub overview: Rc<OverviewKey>,
+    pub master: Arc<MasterKey>,
+    pub overview: Arc<OverviewKey>,
     pub attachments: &'a HashMap<Uuid, (AttachmentData, PathBuf)>,
+    pub skip_trashed: bool,
+}
+
+impl<'a> ItemIterator<'a> {
+    /// Leave out the items which are in the trash. This includes the
+    /// tombstones of purged items.
+    pub fn skip_trashed(mut self) -> Self {
+        self.skip_trashed = true;
+        self
+    }
 }
 
 impl<'a> Iterator for ItemIterator<'a> {
     type Item = Item<'a>;
 
     fn next(&mut self) -> Option<Item<'a>> {
-        self.inner.next().and_then(|item_data| {
-            item_from_data(item_data, self.attachments, self.master.clone(), self.overview.clone()).ok()
-        })
+        // Items which can't be parsed are skipped rather than ending the
+        // iteration. `UnlockedVault::integrity_report` lists them.
+        for item_data in self.inner.by_ref() {
+            if self.skip_trashed && item_data.trashed == Some(true) {
+                continue;
+            }
+            if let Ok(item) = item_from_data(item_data, self.attachments, self.master.clone(), self.overview.clone()) {
+                return Some(item);
+            }
+        }
+
+        None
     }
 }
@@ -5,14 +5,20 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::fmt;
 use std::ops::Deref;
 
+use zeroize::Zeroize;
+
 pub type EncryptionKey = [u8];
 pub type HmacKey = [u8];
 
 /// This contains a pair of keys used for encryption and verification of the
 /// different aspects of the format.
-#[derive(Debug)]
+///
+/// The key material is wiped when the key is dropped and never shows up in
+/// the `Debug` output. With the `mlock` feature the memory holding it is also
+/// locked so it doesn't get swapped out.
 pub struct Key {
     v: Vec<u8>,
 }
@@ -29,16 +35,65 @@ impl Key {
     pub fn verification(&self) -> &HmacKey {
         &self.v[32..64]
     }
+
+    /// Retrieve the raw key material, used when we need to wrap this key
+    /// under another one.
+    #[inline]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.v[..]
+    }
+
+    /// Mutable access to the key material so it can be generated in place
+    #[inline]
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.v[..]
+    }
 }
 
 impl From<Vec<u8>> for Key {
     fn from(v: Vec<u8>) -> Self {
+        lock(v.as_ptr(), v.capacity());
         Key {
             v,
         }
     }
 }
 
+impl Drop for Key {
+    fn drop(&mut self) {
+        let (ptr, len) = (self.v.as_ptr(), self.v.capacity());
+        self.v.zeroize();
+        unlock(ptr, len);
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key { .. }")
+    }
+}
+
+#[cfg(all(feature = "mlock", unix))]
+fn lock(ptr: *const u8, len: usize) {
+    // This is best-effort, we may be over the limit of locked memory
+    unsafe {
+        libc::mlock(ptr as *const libc::c_void, len);
+    }
+}
+
+#[cfg(all(feature = "mlock", unix))]
+fn unlock(ptr: *const u8, len: usize) {
+    unsafe {
+        libc::munlock(ptr as *const libc::c_void, len);
+    }
+}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn lock(_ptr: *const u8, _len: usize) {}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn unlock(_ptr: *const u8, _len: usize) {}
+
 /// Alias we use to indicate we expect the master key
 #[derive(Debug)]
 pub struct MasterKey {
@@ -56,9 +111,7 @@ impl Deref for MasterKey {
 impl From<Vec<u8>> for MasterKey {
     fn from(v: Vec<u8>) -> Self {
         Self {
-            key: Key {
-                v,
-            }
+            key: Key::from(v),
         }
     }
 }
@@ -80,9 +133,7 @@ impl Deref for OverviewKey {
 impl From<Vec<u8>> for OverviewKey {
     fn from(v: Vec<u8>) -> Self {
         Self {
-            key: Key {
-                v,
-            }
+            key: Key::from(v),
         }
     }
 }
@@ -104,9 +155,7 @@ impl Deref for ItemKey {
 impl From<Vec<u8>> for ItemKey {
     fn from(v: Vec<u8>) -> Self {
         Self {
-            key: Key {
-                v,
-            }
+            key: Key::from(v),
         }
     }
 }
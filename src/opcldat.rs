@@ -8,10 +8,16 @@
 use std::io::prelude::*;
 
 use super::{Result, Error};
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 const OPCLDAT_STR: &[u8] = b"OPCLDAT";
 
+/// The version of the format we write
+pub const VERSION: u8 = 1;
+
+/// The size of the header, after which comes the metadata
+pub const HEADER_SIZE: u64 = 16;
+
 #[derive(Debug)]
 pub struct Opcldat {
     pub version: u8,
@@ -38,3 +44,13 @@ pub fn read_header<R: Read>(r: &mut R) -> Result<Opcldat> {
         icon_size,
     })
 }
+
+pub fn write_header<W: Write>(w: &mut W, header: &Opcldat) -> Result<()> {
+    w.write_all(OPCLDAT_STR)?;
+    w.write_u8(header.version)?;
+    w.write_u16::<LittleEndian>(header.metadata_size)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u32::<LittleEndian>(header.icon_size)?;
+
+    Ok(())
+}
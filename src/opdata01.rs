@@ -5,25 +5,60 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::io::Cursor;
+//! The opdata01 format is used for most of the encrypted data in the vault.
+//! It consists of a header with the plaintext length, the IV, the encrypted
+//! (and padded) data and an HMAC over all of it.
+
+use std::io;
+use std::fmt;
+use std::error;
+use std::io::{Cursor, SeekFrom};
 use std::io::prelude::*;
 use std::convert::From;
 
-use super::Result;
-use byteorder::{LittleEndian, ReadBytesExt};
+use super::{Result, Zeroizing};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use zeroize::Zeroize;
 
-use crypto::{verify_data, decrypt_data};
+use crypto::{verify_data, verify_mac, decrypt_data, encrypt_data, hmac, random_bytes, Decrypt, Decrypter};
 
 /// The header for this kind of data
 static OPDATA_STR: &[u8; 8] = b"opdata01";
 
+/// How much data we read at a time when streaming. This must be a whole
+/// number of blocks.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size of everything but the encrypted data: the header, the length, the
+/// IV and the HMAC.
+const OVERHEAD: usize = 8 + 8 + 16 + 32;
+
 #[derive(Debug)]
 pub enum OpdataError {
     InvalidHeader,
     InvalidHmac,
+    /// The data is too short or its length doesn't match the header
+    InvalidLength,
+}
+
+impl fmt::Display for OpdataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            OpdataError::InvalidHeader => write!(f, "not opdata01 data"),
+            OpdataError::InvalidHmac => write!(f, "the data failed HMAC verification"),
+            OpdataError::InvalidLength => write!(f, "the opdata01 data has the wrong length"),
+        }
+    }
 }
 
-pub fn decrypt(data: &[u8], decrypt_key: &[u8], mac_key: &[u8]) -> Result<Vec<u8>> {
+impl error::Error for OpdataError {}
+
+/// Verify and decrypt opdata01-encoded data. The plaintext is wiped from
+/// memory when it's dropped.
+pub fn decrypt(data: &[u8], decrypt_key: &[u8], mac_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if data.len() < OVERHEAD || !(data.len() - OVERHEAD).is_multiple_of(16) {
+        return Err(From::from(OpdataError::InvalidLength));
+    }
     let mut cursor = Cursor::new(data);
 
     // The first step is to hash the data (minus the MAC itself)
@@ -43,9 +78,157 @@ pub fn decrypt(data: &[u8], decrypt_key: &[u8], mac_key: &[u8]) -> Result<Vec<u8
     let iv = &data[16..32];
 
     let crypt_data = &data[32..data.len()-32];
+    if len > crypt_data.len() as u64 {
+        return Err(From::from(OpdataError::InvalidLength));
+    }
 
-    let decrypted = decrypt_data(crypt_data, decrypt_key, iv)?;
+    let decrypted = Zeroizing::new(decrypt_data(crypt_data, decrypt_key, iv)?);
     let unpadded: Vec<u8> = decrypted[crypt_data.len()-(len as usize)..].into();
 
-    Ok(unpadded)
+    Ok(Zeroizing::new(unpadded))
+}
+
+/// Encrypt data into the opdata01 format
+pub fn encrypt(plaintext: &[u8], encrypt_key: &[u8], mac_key: &[u8]) -> Result<Vec<u8>> {
+    // The plaintext is prefixed with random bytes until it fills a whole
+    // number of blocks. There is always at least one byte of padding.
+    let padding_len = 16 - (plaintext.len() % 16);
+    let mut padded = Zeroizing::new(vec![0u8; padding_len + plaintext.len()]);
+    random_bytes(&mut padded[..padding_len])?;
+    padded[padding_len..].copy_from_slice(plaintext);
+
+    let mut iv = [0u8; 16];
+    random_bytes(&mut iv)?;
+
+    let mut data = Vec::with_capacity(32 + padded.len() + 32);
+    data.extend_from_slice(OPDATA_STR);
+    data.write_u64::<LittleEndian>(plaintext.len() as u64)?;
+    data.extend_from_slice(&iv);
+    data.extend(encrypt_data(&padded, encrypt_key, &iv)?);
+
+    let mac = hmac(mac_key, |signer| signer.update(&data))?;
+    data.extend(mac);
+
+    Ok(data)
+}
+
+/// Decrypts opdata01 data from a reader without holding all of it in memory.
+///
+/// The HMAC is verified in a first pass over the data before any plaintext is
+/// returned, so the underlying data should not change while this is in use.
+pub struct Reader<R> {
+    inner: R,
+    decrypter: Decrypter,
+    /// How much ciphertext we have yet to read
+    remaining: u64,
+    /// How many bytes of padding we have yet to skip
+    skip: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Verify the opdata01 data which starts at `start` and extends to the end
+    /// of the reader and prepare to decrypt it.
+    pub fn new(mut inner: R, start: u64, decrypt_key: &[u8], mac_key: &[u8]) -> Result<Reader<R>> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        if end < start + OVERHEAD as u64 || !(end - start).is_multiple_of(16) {
+            return Err(From::from(OpdataError::InvalidLength));
+        }
+        let crypt_len = end - start - OVERHEAD as u64;
+
+        // Hash everything but the MAC itself, which we then compare with
+        inner.seek(SeekFrom::Start(start))?;
+        let computed = {
+            let mut limited = (&mut inner).take(end - start - 32);
+            hmac(mac_key, |signer| {
+                let mut chunk = vec![0u8; CHUNK_SIZE];
+                loop {
+                    let n = limited.read(&mut chunk)?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    signer.update(&chunk[..n])?;
+                }
+            })?
+        };
+        let mut mac = [0u8; 32];
+        inner.read_exact(&mut mac)?;
+        if !verify_mac(&computed, &mac) {
+            return Err(From::from(OpdataError::InvalidHmac));
+        }
+
+        // The data is intact, let's see whether it's well formed now
+        inner.seek(SeekFrom::Start(start))?;
+        let mut header = [0u8; 8];
+        inner.read_exact(&mut header)?;
+        if &header != OPDATA_STR {
+            return Err(From::from(OpdataError::InvalidHeader));
+        }
+
+        let len = inner.read_u64::<LittleEndian>()?;
+        if len > crypt_len {
+            return Err(From::from(OpdataError::InvalidLength));
+        }
+        let mut iv = [0u8; 16];
+        inner.read_exact(&mut iv)?;
+
+        Ok(Reader {
+            inner,
+            decrypter: Decrypter::new(decrypt_key, &iv)?,
+            remaining: crypt_len,
+            skip: (crypt_len - len) as usize,
+            // Allocate the whole buffer upfront so we never leave plaintext
+            // behind when growing it.
+            buf: Vec::with_capacity(CHUNK_SIZE + 16),
+            pos: 0,
+        })
+    }
+
+    /// Decrypt the next chunk of data into our buffer
+    fn fill(&mut self) -> Result<()> {
+        let want = ::std::cmp::min(self.remaining, CHUNK_SIZE as u64) as usize;
+        let mut chunk = vec![0u8; want];
+        self.inner.read_exact(&mut chunk)?;
+        self.remaining -= want as u64;
+
+        self.buf.resize(want + 16, 0);
+        let count = self.decrypter.update(&chunk, &mut self.buf)?;
+        self.buf.truncate(count);
+
+        // The padding is at the start of the plaintext
+        let skip = ::std::cmp::min(self.skip, self.buf.len());
+        self.skip -= skip;
+        self.pos = skip;
+
+        Ok(())
+    }
+}
+
+impl<R> Drop for Reader<R> {
+    fn drop(&mut self) {
+        self.buf.zeroize();
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.fill() {
+                return Err(match e {
+                    super::Error::IoError(e) => e,
+                    e => io::Error::other(e),
+                });
+            }
+        }
+
+        let n = ::std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
 }
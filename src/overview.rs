@@ -1,19 +1,26 @@
 use serde_json as json;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Overview {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ainfo: Option<String>,
-    #[serde(rename = "URLs", default)]
+    #[serde(rename = "URLs", default, skip_serializing_if = "Vec::is_empty")]
     pub urls: Vec<URL>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ps: Option<i64>,
+    /// Fields we don't know about, kept so they survive being written back
+    #[serde(flatten)]
+    pub extra: json::Map<String, json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct URL {
     pub u: String,
@@ -24,4 +31,9 @@ impl Overview {
     pub fn from_slice(s: &[u8]) -> json::Result<Self> {
         json::from_slice(s)
     }
+
+    // Serialize an overview object into a JSON vector
+    pub fn to_vec(&self) -> json::Result<Vec<u8>> {
+        json::to_vec(self)
+    }
 }
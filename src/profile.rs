@@ -10,29 +10,33 @@ use std::fs::File;
 use std::io::prelude::*;
 
 use base64;
-use serde_json;
-use super::{Result};
+use serde_json::{self, Map, Value};
+use super::{Result, Error};
+use super::atomic;
 
 /// The profile data from the file, the names match the keys in the file.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 struct ProfileData {
     pub lastUpdatedBy: String,
     pub updatedAt: i64,
     pub profileName: String,
     pub salt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub passwordHint: Option<String>,
     pub masterKey: String,
     pub iterations: u64,
     pub uuid: String,
     pub overviewKey: String,
     pub createdAt: i64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 /// The information for a particular profile. This includes the encrypted master
 /// and overview keys, which are used to decrypt the details and superficial
 /// information respectively.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Profile {
     pub last_updated_by: String,
     pub updated_at: i64,
@@ -43,7 +47,9 @@ pub struct Profile {
     pub iterations: u64,
     pub uuid: String,
     pub overview_key: Vec<u8>,
-    pub created_at: i64
+    pub created_at: i64,
+    /// Fields we don't know about, kept so they survive being written back
+    pub extra: Map<String, Value>,
 }
 
 impl Profile {
@@ -63,9 +69,36 @@ impl Profile {
             uuid: d.uuid,
             overview_key,
             created_at: d.createdAt,
+            extra: d.extra,
         })
     }
 
+    fn to_profile_data(&self) -> ProfileData {
+        ProfileData {
+            lastUpdatedBy: self.last_updated_by.clone(),
+            updatedAt: self.updated_at,
+            profileName: self.profile_name.clone(),
+            salt: base64::encode(&self.salt),
+            passwordHint: self.password_hint.clone(),
+            masterKey: base64::encode(&self.master_key),
+            iterations: self.iterations,
+            uuid: self.uuid.clone(),
+            overviewKey: base64::encode(&self.overview_key),
+            createdAt: self.created_at,
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Make sure PBKDF2 can be run with this many iterations. Zero isn't allowed
+/// and OpenSSL takes no more than an `i32`, so a profile with any other number
+/// couldn't be unlocked.
+pub fn check_iterations(iterations: u64) -> Result<()> {
+    if iterations == 0 || iterations > i32::MAX as u64 {
+        return Err(Error::IterationsError);
+    }
+
+    Ok(())
 }
 
 // Read in the profile. If the user's master password is given, we also decrypt the master and overview keys
@@ -80,3 +113,11 @@ pub fn read_profile(p: &Path) -> Result<Profile> {
 
     Profile::from_profile_data(profile_data)
 }
+
+/// Write out the profile, wrapped in the same javascript as we found it.
+pub fn write_profile(p: &Path, profile: &Profile) -> Result<()> {
+    let json_str = serde_json::to_string(&profile.to_profile_data())?;
+    let contents = format!("var profile={};", json_str);
+
+    atomic::write(p, contents.as_bytes())
+}
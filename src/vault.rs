@@ -6,17 +6,20 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
-use std::rc::Rc;
-use super::Result;
-use super::{Profile, Folder, Item, Uuid, MasterKey, OverviewKey};
-use super::{folder, profile, item, attachment, crypto, opdata01};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use super::{Result, Error, Context, Zeroizing, OpdataError};
+use super::{Profile, Folder, Item, Category, Detail, Overview, Uuid, Key, MasterKey, OverviewKey};
+use super::{folder, profile, item, attachment, crypto, opdata01, atomic};
 use super::item::{ItemData, ItemIterator};
 use super::attachment::AttachmentData;
+use super::integrity::{IntegrityReport, RejectedItem, BadAttachment, BadFolder, CheckReport, Problem, RepairAction};
 
 /// A locked vault has just been created and has not loaded any items or
 /// attachments. It contains just enough information to try to unseal it.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LockedVault {
     base: PathBuf,
     /// The profile information, including the password hint and master and
@@ -29,7 +32,52 @@ impl LockedVault {
     /// provide a password hint to the user.
     pub fn open(path: &Path) -> Result<LockedVault> {
         let base = path.join("default");
-        let profile = profile::read_profile(&base.join("profile.js"))?;
+        let profile_path = base.join("profile.js");
+        let profile = profile::read_profile(&profile_path).in_file(&profile_path)?;
+
+        Ok(LockedVault {
+            base,
+            profile,
+        })
+    }
+
+    /// Create a new vault with no items or folders. New master and overview
+    /// keys are generated and protected with the given password.
+    pub fn create(path: &Path, password: &[u8], hint: Option<&str>, iterations: u64) -> Result<LockedVault> {
+        let base = path.join("default");
+        let profile_path = base.join("profile.js");
+        if profile_path.exists() {
+            return Err(From::from(io::Error::new(io::ErrorKind::AlreadyExists, "there is already a vault at this path")));
+        }
+        profile::check_iterations(iterations)?;
+        fs::create_dir_all(&base)?;
+
+        let mut salt = vec![0u8; 16];
+        crypto::random_bytes(&mut salt)?;
+        let key = password_key(password, &salt, iterations)?;
+
+        // These are the sizes the official clients use for the key material
+        let mut master_raw = Zeroizing::new(vec![0u8; 256]);
+        crypto::random_bytes(&mut master_raw)?;
+        let mut overview_raw = Zeroizing::new(vec![0u8; 64]);
+        crypto::random_bytes(&mut overview_raw)?;
+
+        let now = item::timestamp();
+        let profile = Profile {
+            last_updated_by: "opvault-rs".to_string(),
+            updated_at: now,
+            profile_name: "default".to_string(),
+            salt,
+            password_hint: hint.map(|h| h.to_string()),
+            master_key: opdata01::encrypt(&master_raw, key.encryption(), key.verification())?,
+            iterations,
+            uuid: item::uuid_string(&crypto::random_uuid()?),
+            overview_key: opdata01::encrypt(&overview_raw, key.encryption(), key.verification())?,
+            created_at: now,
+            extra: Default::default(),
+        };
+        profile::write_profile(&profile_path, &profile)?;
+        atomic::write(&base.join("folders.js"), b"loadFolders({});")?;
 
         Ok(LockedVault {
             base,
@@ -37,27 +85,66 @@ impl LockedVault {
         })
     }
 
-    /// Unlock this vault with the user's master password
+    /// Unlock this vault with the user's master password. This fails if a band
+    /// file is damaged, see `unlock_for_repair`.
     pub fn unlock(self, password: &[u8]) -> Result<UnlockedVault> {
         let (master, overview) = self.decrypt_keys(password)?;
-        UnlockedVault::new(self.base, self.profile, Rc::new(master), Rc::new(overview))
+        UnlockedVault::new(self.base, self.profile, Arc::new(master), Arc::new(overview), false)
+    }
+
+    /// Unlock this vault even if band files are damaged, e.g. only partly
+    /// written, keeping the items which can still be read from them. The
+    /// damage is listed in `integrity_report` and `repair` rewrites the files.
+    pub fn unlock_for_repair(self, password: &[u8]) -> Result<UnlockedVault> {
+        let (master, overview) = self.decrypt_keys(password)?;
+        UnlockedVault::new(self.base, self.profile, Arc::new(master), Arc::new(overview), true)
+    }
+
+    /// Unlock this vault, failing if any item, attachment or folder fails
+    /// verification rather than leaving it out. This reads every attachment in
+    /// full.
+    pub fn unlock_strict(self, password: &[u8]) -> Result<UnlockedVault> {
+        let vault = self.unlock(password)?;
+        let report = vault.integrity_report()?;
+        if !report.is_clean() {
+            return Err(Error::IntegrityError(report));
+        }
+
+        Ok(vault)
     }
 
     /// Decrypt and derive the master and overview keys given the user's master
     /// password. The master keys can be used to retrieve item details and the
     /// overview keys decrypt item and folder overview data.
+    ///
+    /// A wrong password makes both keys fail verification. If only one of them
+    /// does, it's the profile which is damaged instead.
     fn decrypt_keys(&self, password: &[u8]) -> Result<(MasterKey, OverviewKey)> {
-        let key = crypto::pbkdf2(password, &self.profile.salt[..], self.profile.iterations as usize)?;
-        let decrypt_key = &key[..32];
-        let hmac_key = &key[32..];
+        let key = password_key(password, &self.profile.salt[..], self.profile.iterations)?;
 
-        let master_key = derive_key(&self.profile.master_key[..], decrypt_key, hmac_key)?;
-        let overview_key = derive_key(&self.profile.overview_key[..], decrypt_key, hmac_key)?;
+        let master_key = derive_key(&self.profile.master_key[..], key.encryption(), key.verification());
+        let overview_key = derive_key(&self.profile.overview_key[..], key.encryption(), key.verification());
 
-        Ok((master_key.into(), overview_key.into()))
+        let profile_path = self.base.join("profile.js");
+        match (master_key, overview_key) {
+            (Ok(master_key), Ok(overview_key)) => Ok((master_key.into(), overview_key.into())),
+            (Err(ref m), Err(ref o)) if is_bad_hmac(m) && is_bad_hmac(o) => Err(Error::WrongPassword),
+            (Err(e), _) => Err(e).in_field("masterKey").in_file(&profile_path),
+            (_, Err(e)) => Err(e).in_field("overviewKey").in_file(&profile_path),
+        }
     }
 }
 
+/// Derive the key which protects the master and overview keys from the user's
+/// password
+fn password_key(password: &[u8], salt: &[u8], iterations: u64) -> Result<Key> {
+    crypto::pbkdf2(password, salt, iterations as usize)
+}
+
+fn is_bad_hmac(e: &Error) -> bool {
+    matches!(*e, Error::OpdataError(OpdataError::InvalidHmac))
+}
+
 /// Derive a key from its opdata01-encoded source
 fn derive_key(data: &[u8], decrypt_key: &[u8], hmac_key: &[u8]) -> Result<Vec<u8>> {
     let key_plain = opdata01::decrypt(data, decrypt_key, hmac_key)?;
@@ -68,6 +155,9 @@ fn derive_key(data: &[u8], decrypt_key: &[u8], hmac_key: &[u8]) -> Result<Vec<u8
 
 /// An unlocked vault has loaded the encrypted items and attachments and
 /// contains the keys necessary to decrypt the contents.
+///
+/// The vault can be shared between threads, e.g. by wrapping it in an `Arc`,
+/// so it only needs to be unlocked once.
 #[derive(Debug)]
 pub struct UnlockedVault {
     base: PathBuf,
@@ -81,19 +171,26 @@ pub struct UnlockedVault {
     attachments: HashMap<Uuid, (AttachmentData, PathBuf)>,
 
     /// Master key
-    master: Rc<MasterKey>,
+    master: Arc<MasterKey>,
     /// Overview key
-    overview: Rc<OverviewKey>,
+    overview: Arc<OverviewKey>,
 }
 
 impl UnlockedVault {
     /// Read the encrypted data in a vault. We assume the profile is "default"
     /// which is the only one currently in use. This is primarily for use by
     /// `LockedVault`'s `unlock` method.
-    fn new(base: PathBuf, profile: Profile, master: Rc<MasterKey>, overview: Rc<OverviewKey>) -> Result<UnlockedVault> {
-        let folders = folder::read_folders(&base.join("folders.js"), overview.clone())?;
-        let attachments = attachment::read_attachments(&base)?;
-        let items = item::read_items(&base, overview.clone())?;
+    fn new(base: PathBuf, profile: Profile, master: Arc<MasterKey>, overview: Arc<OverviewKey>, salvage: bool) -> Result<UnlockedVault> {
+        let folders_path = base.join("folders.js");
+        let folders = folder::read_folders(&folders_path, overview.clone()).in_file(&folders_path)?;
+        let (attachments, _) = attachment::read_attachments(&base)?;
+        let bands = item::read_items(&base, overview.clone(), salvage)?;
+        if !salvage {
+            if let Some(band) = bands.damaged.into_iter().next() {
+                return Err(band.error).in_file(&band.file);
+            }
+        }
+        let items = bands.items;
 
         Ok(UnlockedVault {
             base,
@@ -106,21 +203,370 @@ impl UnlockedVault {
         })
     }
 
-    pub fn get_item(&self, id: &Uuid) -> Option<Item> {
-        let data = self.items.get(id);
-        if let Some(item_data) = data {
-            item::item_from_data(item_data, &self.attachments, self.master.clone(), self.overview.clone()).ok()
-        } else {
-            None
+    /// Lock the vault again. The keys are wiped from memory once nothing else
+    /// refers to them. `Attachment`s and clones of `Folder`s share the item
+    /// and overview keys rather than borrowing the vault, so any which are
+    /// still around keep those keys alive after locking. Drop them first if
+    /// the keys need to be gone.
+    pub fn lock(self) -> LockedVault {
+        LockedVault {
+            base: self.base,
+            profile: self.profile,
         }
     }
 
-    pub fn get_items(&self) -> ItemIterator {
+    /// Find everything in the vault which fails verification. The band files
+    /// and attachments are read again, and each attachment is read in full to
+    /// check its contents.
+    ///
+    /// Items which verify but can't be parsed are also listed, as `get_item`
+    /// and `get_items` leave them out.
+    pub fn integrity_report(&self) -> Result<IntegrityReport> {
+        let bands = item::read_items(&self.base, self.overview.clone(), true)?;
+        let mut rejected_items = bands.rejected;
+        for (id, data) in &self.items {
+            if let Err(error) = item::item_from_data(data, &self.attachments, self.master.clone(), self.overview.clone()) {
+                let uuid = item::uuid_string(id);
+                rejected_items.push(RejectedItem { file: item::band_path(&self.base, &uuid), uuid, error });
+            }
+        }
+
+        let (attachments, mut bad_attachments) = attachment::read_attachments(&self.base)?;
+        for (data, path) in attachments.values() {
+            // Attachments without an item have nothing to verify them with
+            let item_data = match self.items.get(&data.itemUUID) {
+                Some(item_data) => item_data,
+                None => continue,
+            };
+            let res = item::item_key(item_data, &self.master)
+                .and_then(|key| attachment::from_data(data, path.clone(), Arc::new(key), self.overview.clone()))
+                .and_then(|att| att.verify());
+            if let Err(error) = res {
+                bad_attachments.push(BadAttachment { file: path.clone(), uuid: Some(data.uuid), error });
+            }
+        }
+
+        let mut bad_folders = Vec::new();
+        for (uuid, folder) in &self.folders {
+            if let Err(error) = folder.overview() {
+                bad_folders.push(BadFolder { uuid: *uuid, error });
+            }
+        }
+
+        Ok(IntegrityReport {
+            damaged_bands: bands.damaged,
+            rejected_items,
+            bad_attachments,
+            bad_folders,
+        })
+    }
+
+    /// Check the whole vault. On top of what `integrity_report` finds, this
+    /// looks for attachments and folders which don't match up with the items,
+    /// items stored more than once or in the wrong band file, attachments
+    /// whose header doesn't match their contents, and items whose overview or
+    /// details can't be decrypted.
+    pub fn check(&self) -> Result<CheckReport> {
+        let integrity = self.integrity_report()?;
+        let mut problems = Vec::new();
+
+        let mut locations: HashMap<Uuid, Vec<PathBuf>> = HashMap::new();
+        for band in item::read_raw_bands(&self.base, true)? {
+            for key in band.entries.keys() {
+                // Entries with invalid UUIDs are already rejected items
+                if let Ok(uuid) = Uuid::parse_str(key) {
+                    let expected = item::band_path(&self.base, key);
+                    if expected != band.path {
+                        problems.push(Problem::MisplacedItem { uuid, file: band.path.clone(), expected });
+                    }
+                    locations.entry(uuid).or_default().push(band.path.clone());
+                }
+            }
+        }
+        for (uuid, files) in &locations {
+            if files.len() > 1 {
+                problems.push(Problem::DuplicateItem { uuid: *uuid, files: files.clone() });
+            }
+        }
+
+        for item in self.get_items() {
+            if let Some(folder) = item.folder {
+                if !self.folders.contains_key(&folder) {
+                    problems.push(Problem::UnknownFolder { item: item.uuid, folder });
+                }
+            }
+            if let Err(error) = item.overview().and_then(|_| item.detail()) {
+                problems.push(Problem::UnreadableItem { uuid: item.uuid, error });
+            }
+        }
+
+        for (uuid, (data, path)) in &self.attachments {
+            // Items which fail verification still own their attachments
+            if !locations.contains_key(&data.itemUUID) {
+                problems.push(Problem::OrphanAttachment { file: path.clone(), uuid: *uuid, item: data.itemUUID });
+            }
+            // Attachments we can't read at all are in the integrity report
+            if let Ok(Some(reason)) = attachment::check_layout(path, data) {
+                problems.push(Problem::BadAttachmentLayout { file: path.clone(), reason });
+            }
+        }
+
+        Ok(CheckReport {
+            integrity,
+            problems,
+        })
+    }
+
+    /// Fix what `check` finds where we can do so without losing data. Damaged
+    /// band files are rewritten with the items which can still be read, items
+    /// are moved into the right band file, and attachments without an item
+    /// are moved out of the vault. Damaged band files and the attachments are
+    /// kept in the quarantine directory.
+    ///
+    /// Returns what was done.
+    pub fn repair(&mut self, quarantine: &Path) -> Result<Vec<RepairAction>> {
+        let mut actions = item::repair_bands(&self.base, &self.overview, quarantine)?;
+        self.items = item::read_items(&self.base, self.overview.clone(), false)?.items;
+
+        // An item which fails verification still owns its attachments, so
+        // only those without any entry in the band files are moved out
+        let mut on_disk = HashSet::new();
+        for band in item::read_raw_bands(&self.base, false)? {
+            on_disk.extend(band.entries.keys().filter_map(|key| Uuid::parse_str(key).ok()));
+        }
+        let orphans: Vec<Uuid> = self.attachments.iter()
+            .filter(|(_, (a, _))| !on_disk.contains(&a.itemUUID))
+            .map(|(k, _)| *k)
+            .collect();
+        for uuid in orphans {
+            if let Some((_, file)) = self.attachments.remove(&uuid) {
+                let to = atomic::backup_path(quarantine, &file)?;
+                atomic::move_file(&file, &to)?;
+                actions.push(RepairAction::QuarantinedAttachment { file, to });
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Look up an item by its UUID. An item which is in the vault but can't be
+    /// parsed is an error rather than `None`.
+    pub fn get_item(&self, id: &Uuid) -> Result<Option<Item<'_>>> {
+        match self.items.get(id) {
+            Some(item_data) => {
+                let item = item::item_from_data(item_data, &self.attachments, self.master.clone(), self.overview.clone());
+                item.in_item(id).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_items(&self) -> ItemIterator<'_> {
         ItemIterator {
             inner: self.items.values(),
             master: self.master.clone(),
             overview: self.overview.clone(),
             attachments: &self.attachments,
+            skip_trashed: false,
+        }
+    }
+
+    /// Encrypt a new item and store it in the vault. A new item key is
+    /// generated for it and the item is written into the band file its UUID
+    /// belongs in. Returns the UUID of the new item.
+    pub fn add_item(&mut self, category: Category, overview: &Overview, detail: &Detail) -> Result<Uuid> {
+        let uuid = crypto::random_uuid()?;
+        let data = item::new_item_data(&uuid, category, overview, detail, &self.master, &self.overview)?;
+        item::write_item(&self.base, &data)?;
+        self.items.insert(uuid, data);
+
+        Ok(uuid)
+    }
+
+    /// Replace an item's overview and details. The new data is encrypted
+    /// under the item's existing key and its band file is rewritten.
+    pub fn update_item(&mut self, id: &Uuid, overview: &Overview, detail: &Detail) -> Result<()> {
+        let (master, overview_key) = (self.master.clone(), self.overview.clone());
+        self.modify_item(id, |data| item::update_item_data(data, overview, detail, &master, &overview_key))
+    }
+
+    /// Move an item to the trash. It stays in the vault and can be restored.
+    pub fn trash_item(&mut self, id: &Uuid) -> Result<()> {
+        let overview_key = self.overview.clone();
+        self.modify_item(id, |data| item::set_trashed(data, true, &overview_key))
+    }
+
+    /// Take an item back out of the trash
+    pub fn restore_item(&mut self, id: &Uuid) -> Result<()> {
+        let overview_key = self.overview.clone();
+        self.modify_item(id, |data| item::set_trashed(data, false, &overview_key))
+    }
+
+    /// Delete an item for good. Its attachments are removed and the item is
+    /// replaced by a tombstone so other clients know it's gone.
+    pub fn purge_item(&mut self, id: &Uuid) -> Result<()> {
+        let (master, overview_key) = (self.master.clone(), self.overview.clone());
+        self.modify_item(id, |data| item::tombstone(data, &master, &overview_key))?;
+
+        let attachments: Vec<Uuid> = self.attachments.iter()
+            .filter(|(_, (a, _))| a.itemUUID == *id)
+            .map(|(k, _)| *k)
+            .collect();
+        for att in attachments {
+            if let Some((_, path)) = self.attachments.remove(&att) {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt a file and attach it to an item. The icon is optional and is
+    /// what the official clients show as a preview. Returns the UUID of the
+    /// new attachment.
+    pub fn add_attachment(&mut self, item: &Uuid, name: &str, content: &[u8], icon: Option<&[u8]>) -> Result<Uuid> {
+        let key = match self.items.get(item) {
+            Some(data) => item::item_key(data, &self.master)?,
+            None => return Err(Error::ItemError),
+        };
+        let (data, path) = attachment::new_attachment(&self.base, item, name, content, icon, &key, &self.overview)?;
+        let uuid = data.uuid;
+        self.attachments.insert(uuid, (data, path));
+
+        Ok(uuid)
+    }
+
+    /// Replace the contents of an attachment. Its name and icon are kept.
+    pub fn replace_attachment(&mut self, id: &Uuid, content: &[u8]) -> Result<()> {
+        let (data, path) = match self.attachments.get(id) {
+            Some(x) => x.clone(),
+            None => return Err(Error::AttachmentError),
+        };
+        let key = match self.items.get(&data.itemUUID) {
+            Some(item_data) => item::item_key(item_data, &self.master)?,
+            None => return Err(Error::ItemError),
+        };
+        let replaced = attachment::replace_content(&data, &path, content, &key)?;
+        self.attachments.insert(*id, (replaced, path));
+
+        Ok(())
+    }
+
+    /// Delete an attachment from the vault
+    pub fn remove_attachment(&mut self, id: &Uuid) -> Result<()> {
+        let path = match self.attachments.get(id) {
+            Some((_, path)) => path.clone(),
+            None => return Err(Error::AttachmentError),
+        };
+        fs::remove_file(&path)?;
+        self.attachments.remove(id);
+
+        Ok(())
+    }
+
+    /// Move an item into the given folder, or out of any folder if `None`
+    pub fn move_to_folder(&mut self, id: &Uuid, folder: Option<&Uuid>) -> Result<()> {
+        if let Some(folder) = folder {
+            if !self.folders.contains_key(folder) {
+                return Err(Error::FolderError);
+            }
+        }
+
+        let overview_key = self.overview.clone();
+        self.modify_item(id, |data| item::set_folder(data, folder, &overview_key))
+    }
+
+    /// Create a new, empty folder. Returns the UUID of the new folder.
+    pub fn create_folder(&mut self, title: &str) -> Result<Uuid> {
+        let folder = folder::new_folder(title, self.overview.clone())?;
+        folder::write_folder(&self.base.join("folders.js"), &folder)?;
+        let uuid = folder.uuid;
+        self.folders.insert(uuid, folder);
+
+        Ok(uuid)
+    }
+
+    /// Change the title of a folder
+    pub fn rename_folder(&mut self, id: &Uuid, title: &str) -> Result<()> {
+        let mut folder = match self.folders.get(id) {
+            Some(folder) => folder.clone(),
+            None => return Err(Error::FolderError),
+        };
+        folder::rename(&mut folder, title)?;
+        folder::write_folder(&self.base.join("folders.js"), &folder)?;
+        self.folders.insert(*id, folder);
+
+        Ok(())
+    }
+
+    /// Delete a folder. The items in it are kept, but they are moved out of
+    /// the folder first.
+    pub fn delete_folder(&mut self, id: &Uuid) -> Result<()> {
+        if !self.folders.contains_key(id) {
+            return Err(Error::FolderError);
+        }
+
+        let contained: Vec<Uuid> = self.items.iter()
+            .filter(|(_, data)| item::in_folder(data, id))
+            .map(|(k, _)| *k)
+            .collect();
+        for item_id in contained {
+            self.move_to_folder(&item_id, None)?;
         }
+
+        folder::remove_folder(&self.base.join("folders.js"), id)?;
+        self.folders.remove(id);
+
+        Ok(())
+    }
+
+    /// Change the vault's master password, given the current one. The master
+    /// and overview keys stay the same, they are encrypted under a key derived
+    /// from the new password with a new salt, so the items themselves are left
+    /// untouched.
+    pub fn change_password(&mut self, current: &[u8], password: &[u8], iterations: u64) -> Result<()> {
+        profile::check_iterations(iterations)?;
+
+        // The key derived from the password isn't kept around while the vault
+        // is unlocked, so it has to be derived again.
+        let current_key = password_key(current, &self.profile.salt[..], self.profile.iterations)?;
+        let master_raw = opdata01::decrypt(&self.profile.master_key[..], current_key.encryption(), current_key.verification());
+        let overview_raw = opdata01::decrypt(&self.profile.overview_key[..], current_key.encryption(), current_key.verification());
+        let (master_raw, overview_raw) = match (master_raw, overview_raw) {
+            (Ok(m), Ok(o)) => (m, o),
+            (Err(ref m), Err(ref o)) if is_bad_hmac(m) && is_bad_hmac(o) => return Err(Error::WrongPassword),
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        };
+
+        let mut salt = vec![0u8; 16];
+        crypto::random_bytes(&mut salt)?;
+        let key = password_key(password, &salt, iterations)?;
+
+        let mut profile = self.profile.clone();
+        profile.master_key = opdata01::encrypt(&master_raw, key.encryption(), key.verification())?;
+        profile.overview_key = opdata01::encrypt(&overview_raw, key.encryption(), key.verification())?;
+        profile.salt = salt;
+        profile.iterations = iterations;
+        profile.updated_at = item::timestamp();
+        profile::write_profile(&self.base.join("profile.js"), &profile)?;
+
+        self.profile = profile;
+
+        Ok(())
+    }
+
+    /// Apply a change to a copy of an item and write it out. The in-memory
+    /// item is only replaced once it has been written.
+    fn modify_item<F>(&mut self, id: &Uuid, f: F) -> Result<()>
+        where F: FnOnce(&mut ItemData) -> Result<()> {
+        let mut data = match self.items.get(id) {
+            Some(data) => data.clone(),
+            None => return Err(Error::ItemError),
+        };
+        f(&mut data).in_item(id)?;
+        item::write_item(&self.base, &data)?;
+        self.items.insert(*id, data);
+
+        Ok(())
     }
 }
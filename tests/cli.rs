@@ -0,0 +1,267 @@
+// Copyright 2016 opvault-rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Run the `opvault` tool against the sample vault
+
+#![cfg(feature = "cli")]
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Run a command against the given vault, expecting it to succeed
+fn opvault(vault: &str, args: &[&str]) -> String {
+    opvault_input(vault, args, b"")
+}
+
+/// Run a command with the input on its stdin, expecting it to succeed
+fn opvault_input(vault: &str, args: &[&str], input: &[u8]) -> String {
+    let output = run_input(vault, args, input);
+    assert!(output.status.success(), "opvault {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+
+    String::from_utf8(output.stdout).expect("utf-8 output")
+}
+
+fn run(vault: &str, args: &[&str]) -> Output {
+    run_input(vault, args, b"")
+}
+
+fn run_input(vault: &str, args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_opvault"))
+        .arg("--vault")
+        .arg(vault)
+        .args(args)
+        .env("OPVAULT_PASSWORD", "freddy")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run opvault");
+    child.stdin.take().expect("stdin").write_all(input).expect("write stdin");
+
+    child.wait_with_output().expect("run opvault")
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("opvault-cli-{}-{}", name, ::std::process::id()))
+}
+
+/// Copy the sample vault somewhere we can change it
+fn copy_vault(name: &str) -> PathBuf {
+    let dest = temp_path(name);
+    let _ = fs::remove_dir_all(&dest);
+    fs::create_dir_all(dest.join("default")).expect("create dir");
+    for entry in fs::read_dir("onepassword_data/default").expect("read dir") {
+        let entry = entry.expect("entry");
+        fs::copy(entry.path(), dest.join("default").join(entry.file_name())).expect("copy");
+    }
+
+    dest
+}
+
+#[test]
+fn list() {
+    let out = opvault("onepassword_data", &["list"]);
+    assert_eq!(27, out.lines().count());
+    assert!(out.lines().any(|l| l == "13C8E12AC8E54B1F873BAB0824E521BC\tLogin\tHulu"), "{}", out);
+
+    let out = opvault("onepassword_data", &["list", "--trashed"]);
+    assert_eq!(29, out.lines().count());
+}
+
+#[test]
+fn show() {
+    let out = opvault("onepassword_data", &["show", "Hulu"]);
+    assert!(out.contains("UUID: 13C8E12AC8E54B1F873BAB0824E521BC\n"), "{}", out);
+    assert!(out.contains("username: wendy@appleseed.com\n"), "{}", out);
+    assert!(out.contains("password: ********\n"), "{}", out);
+
+    let out = opvault("onepassword_data", &["show", "--reveal", "13C8E12AC8E54B1F873BAB0824E521BC"]);
+    assert!(out.contains("password: frirp7i1ob7wig4d\n"), "{}", out);
+
+    let out = run("onepassword_data", &["show", "no such item"]);
+    assert_eq!(Some(1), out.status.code());
+
+    // The submit button isn't a field worth showing
+    let out = opvault("onepassword_data", &["show", "Dropbox"]);
+    assert!(!out.contains("Log in"), "{}", out);
+    assert!(out.lines().all(|l| !l.starts_with(": ")), "{}", out);
+}
+
+#[test]
+fn show_hides_password_fields() {
+    let path = copy_vault("hidden");
+    let vault = path.to_str().expect("path");
+
+    let json = br#"{"detail": {"fields": [{"type": "P", "name": "pin", "value": "1234"}, {"type": "T", "name": "user", "value": "bob"}]}}"#;
+    let uuid = opvault_input(vault, &["add", "login", "--title", "PIN", "--json"], json);
+    let out = opvault(vault, &["show", uuid.trim()]);
+    assert!(out.contains("pin: ********\n"), "{}", out);
+    assert!(out.contains("user: bob\n"), "{}", out);
+    assert!(!out.contains("1234"), "{}", out);
+    assert!(opvault(vault, &["show", "--reveal", uuid.trim()]).contains("pin: 1234\n"));
+
+    fs::remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn password_fd() {
+    let path = copy_vault("password-fd");
+    let vault = path.to_str().expect("path");
+
+    // The password and the item both come from stdin
+    let uuid = opvault_input(vault, &["--password-fd", "0", "add", "note", "--json"], b"freddy\n{\"overview\": {\"title\": \"Piped\"}}");
+    assert_eq!("Piped\n", opvault(vault, &["get", uuid.trim(), "title"]));
+
+    let out = run(vault, &["--password-fd", "99", "list"]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--password-fd"));
+
+    fs::remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn extract() {
+    let out = opvault("onepassword_data", &["attachments", "F2DB5DA3FCA64372A751E0E85C67A538"]);
+    assert!(out.contains("23F6167DC1FB457A8DE7033ACDCD06DB\t54093\t"), "{}", out);
+
+    let path = temp_path("extract");
+    let _ = fs::remove_file(&path);
+    opvault("onepassword_data", &["extract", "23F6167DC1FB457A8DE7033ACDCD06DB", "-o", path.to_str().expect("path")]);
+    let contents = fs::read(&path).expect("read attachment");
+    assert_eq!(54093, contents.len());
+    // a JPEG
+    assert_eq!(&[0xff, 0xd8], &contents[..2]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).expect("metadata").permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+    fs::remove_file(&path).expect("cleanup");
+
+    // A file which is already there is made private before it's written
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(&path, b"old contents").expect("create file");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).expect("chmod");
+        opvault("onepassword_data", &["extract", "23F6167DC1FB457A8DE7033ACDCD06DB", "-o", path.to_str().expect("path")]);
+        assert_eq!(0o600, fs::metadata(&path).expect("metadata").permissions().mode() & 0o777);
+        assert_eq!(contents, fs::read(&path).expect("read attachment"));
+        fs::remove_file(&path).expect("cleanup");
+    }
+
+    let out = run("onepassword_data", &["extract", "23F6167DC1FB457A8DE7033ACDCD06DB"]);
+    assert!(out.status.success());
+    assert_eq!(contents, out.stdout);
+
+    // Into a directory under the attachment's own name
+    let dir = temp_path("extract-dir");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).expect("create dir");
+    opvault("onepassword_data", &["extract", "23F6167DC1FB457A8DE7033ACDCD06DB", "-o", dir.to_str().expect("path")]);
+    let extracted = fs::read(dir.join("maria_sibylla_merians_366th_birthday_-1256008-hp.jpg")).expect("read attachment");
+    assert_eq!(contents, extracted);
+    fs::remove_dir_all(&dir).expect("cleanup");
+}
+
+#[test]
+fn extract_unsafe_name() {
+    let path = copy_vault("unsafe");
+    let vault = path.to_str().expect("path");
+
+    let att = opvault_input(vault, &["attach", "--name", "../escaped", "Hulu", "-"], b"evil");
+    let dir = path.join("out");
+    fs::create_dir(&dir).expect("create dir");
+    opvault(vault, &["extract", att.trim(), "-o", dir.to_str().expect("path")]);
+    assert!(!path.join("escaped").exists());
+    assert_eq!(b"evil", &fs::read(dir.join(".._escaped")).expect("read attachment")[..]);
+
+    fs::remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn write_commands() {
+    let path = copy_vault("write");
+    let vault = path.to_str().expect("path");
+
+    let uuid = opvault(vault, &["add", "login", "--title", "Test", "--username", "bob", "--password", "hunter2", "--tag", "one", "--folder", "Business"]);
+    let uuid = uuid.trim();
+    let out = opvault(vault, &["show", "--reveal", uuid]);
+    for line in &["Title: Test", "Folder: Business", "Tags: one", "username: bob", "password: hunter2"] {
+        assert!(out.lines().any(|l| l == *line), "{}", out);
+    }
+
+    opvault_input(vault, &["edit", uuid, "--title", "Edited", "--password", "-"], b"newpw\n");
+    assert_eq!("Edited\n", opvault(vault, &["get", uuid, "title"]));
+    assert_eq!("newpw\n", opvault(vault, &["get", uuid, "password"]));
+    opvault(vault, &["edit", uuid, "--generate", "--length", "30"]);
+    assert_eq!(31, opvault(vault, &["get", uuid, "password"]).len());
+
+    opvault(vault, &["mv", uuid, "Social"]);
+    assert!(opvault(vault, &["show", uuid]).contains("Folder: Social\n"));
+    opvault(vault, &["mv", uuid, "-"]);
+    assert!(!opvault(vault, &["show", uuid]).contains("Folder:"));
+
+    let att = opvault_input(vault, &["attach", "--name", "a.txt", uuid, "-"], b"hello\n");
+    let att = att.trim();
+    assert_eq!(format!("{}\t6\ta.txt\n", att), opvault(vault, &["attachments", uuid]));
+    assert_eq!("hello\n", opvault(vault, &["extract", att]));
+
+    opvault(vault, &["rm", uuid]);
+    assert!(!opvault(vault, &["list"]).contains(uuid));
+    assert!(opvault(vault, &["list", "--trashed"]).contains(&format!("{}\tLogin\tEdited", uuid)));
+    opvault(vault, &["rm", "--purge", uuid]);
+    assert!(opvault(vault, &["list", "--trashed"]).contains(&format!("{}\tTombstone\t", uuid)));
+    assert!(opvault(vault, &["attachments", uuid]).is_empty());
+
+    // The sample vault already has items in folders which don't exist
+    let out = run(vault, &["check"]);
+    let report = String::from_utf8(out.stdout).expect("utf-8 output");
+    assert!(report.lines().all(|l| l.contains("unknown folder")), "{}", report);
+    fs::remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn json_input() {
+    let path = copy_vault("json");
+    let vault = path.to_str().expect("path");
+
+    let json = br#"{"overview": {"title": "From JSON", "tags": ["a"]}, "detail": {"notesPlain": "secret"}}"#;
+    let uuid = opvault_input(vault, &["add", "note", "--json"], json);
+    let uuid = uuid.trim();
+    assert_eq!("From JSON\n", opvault(vault, &["get", uuid, "title"]));
+    assert_eq!("secret\n", opvault(vault, &["get", uuid, "notes"]));
+
+    // Only the part that's given is replaced, and the options apply on top
+    opvault_input(vault, &["edit", uuid, "--json", "--title", "Renamed"], br#"{"detail": {"notesPlain": "changed"}}"#);
+    assert_eq!("Renamed\n", opvault(vault, &["get", uuid, "title"]));
+    assert_eq!("changed\n", opvault(vault, &["get", uuid, "notes"]));
+    assert!(opvault(vault, &["show", uuid]).contains("Tags: a\n"));
+
+    let out = run_input(vault, &["edit", uuid, "--json", "--password", "-"], b"{}");
+    assert_eq!(Some(2), out.status.code());
+    let out = run_input(vault, &["edit", uuid, "--json"], b"not json");
+    assert_eq!(Some(1), out.status.code());
+
+    fs::remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn generate() {
+    let out = opvault("onepassword_data", &["generate", "--length", "40", "--no-symbols"]);
+    let password = out.trim_end_matches('\n');
+    assert_eq!(40, password.len());
+    assert!(password.chars().all(|c| c.is_ascii_alphanumeric()), "{}", password);
+    assert_ne!(out, opvault("onepassword_data", &["generate", "--length", "40", "--no-symbols"]));
+
+    let out = opvault("onepassword_data", &["generate"]);
+    assert_eq!(24, out.trim_end_matches('\n').len());
+}